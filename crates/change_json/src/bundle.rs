@@ -0,0 +1,195 @@
+use crate::{decompress, BundleHeader, ChangeJson, VersionError};
+use serde::Deserialize;
+use std::{
+    fmt,
+    io::{self, BufReader, Read},
+    str::FromStr,
+};
+
+/// Prefix of bundles in the binary format. JSON bundles always start with `{`.
+const BINARY_MAGIC: &[u8; 4] = b"RAB\0";
+
+/// Encodings a [`ChangeJson`] can be stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleFormat {
+    Json,
+    /// `bincode` encoding, prefixed with a magic number.
+    Binary,
+}
+
+impl BundleFormat {
+    pub fn detect(bytes: &[u8]) -> BundleFormat {
+        if bytes.starts_with(BINARY_MAGIC) {
+            BundleFormat::Binary
+        } else {
+            BundleFormat::Json
+        }
+    }
+}
+
+impl FromStr for BundleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(BundleFormat::Json),
+            "binary" => Ok(BundleFormat::Binary),
+            _ => Err(format!("unknown bundle format `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for BundleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleFormat::Json => write!(f, "json"),
+            BundleFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BundleError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Binary(bincode::Error),
+    Version(VersionError),
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::Io(err) => write!(f, "failed to read bundle: {}", err),
+            BundleError::Json(err) => write!(f, "invalid JSON bundle: {}", err),
+            BundleError::Binary(err) => write!(f, "invalid binary bundle: {}", err),
+            BundleError::Version(err) => write!(f, "incompatible bundle: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+impl From<io::Error> for BundleError {
+    fn from(err: io::Error) -> Self {
+        BundleError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for BundleError {
+    fn from(err: serde_json::Error) -> Self {
+        BundleError::Json(err)
+    }
+}
+
+impl From<bincode::Error> for BundleError {
+    fn from(err: bincode::Error) -> Self {
+        BundleError::Binary(err)
+    }
+}
+
+impl From<VersionError> for BundleError {
+    fn from(err: VersionError) -> Self {
+        BundleError::Version(err)
+    }
+}
+
+/// Only the header of a bundle, so that bundles of another version fail with
+/// a [`VersionError`] instead of a decoding error.
+#[derive(Deserialize)]
+struct HeaderOnly {
+    #[serde(default = "BundleHeader::unversioned")]
+    header: BundleHeader,
+}
+
+impl ChangeJson {
+    pub fn to_bytes(&self, format: BundleFormat) -> Result<Vec<u8>, BundleError> {
+        match format {
+            BundleFormat::Json => Ok(serde_json::to_vec(self)?),
+            BundleFormat::Binary => {
+                let mut bytes = BINARY_MAGIC.to_vec();
+                bincode::serialize_into(&mut bytes, self)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Reads a bundle in either format, see [`BundleFormat::detect`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<ChangeJson, BundleError> {
+        match BundleFormat::detect(bytes) {
+            BundleFormat::Json => match serde_json::from_slice::<ChangeJson>(bytes) {
+                Ok(json) => {
+                    json.header.check()?;
+                    Ok(json)
+                }
+                Err(err) => {
+                    // only parse the document a second time if it failed, a
+                    // bundle of another version should report that instead
+                    serde_json::from_slice::<HeaderOnly>(bytes)?
+                        .header
+                        .check()?;
+                    Err(err.into())
+                }
+            },
+            BundleFormat::Binary => {
+                let bytes = &bytes[BINARY_MAGIC.len()..];
+                // `ChangeJson` starts with its header, so this only decodes
+                // the prefix of the bundle.
+                bincode::deserialize::<HeaderOnly>(bytes)?.header.check()?;
+                Ok(bincode::deserialize(bytes)?)
+            }
+        }
+    }
+
+    /// Reads a possibly compressed bundle in either format.
+    pub fn from_reader<R: Read>(reader: R) -> Result<ChangeJson, BundleError> {
+        let mut bytes = Vec::new();
+        decompress(BufReader::new(reader))?.read_to_end(&mut bytes)?;
+        ChangeJson::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundle_formats_round_trip() {
+        let json = ChangeJson::default();
+        for format in [BundleFormat::Json, BundleFormat::Binary].iter() {
+            let bytes = json.to_bytes(*format).unwrap();
+            assert_eq!(BundleFormat::detect(&bytes), *format);
+            let read = ChangeJson::from_bytes(&bytes).unwrap();
+            assert_eq!(read.header(), json.header());
+        }
+    }
+
+    #[test]
+    fn bundle_with_other_schema_is_refused() {
+        let json = ChangeJson::default().with_header(BundleHeader {
+            schema_version: crate::SCHEMA_VERSION + 1,
+            ..BundleHeader::current()
+        });
+        for format in [BundleFormat::Json, BundleFormat::Binary].iter() {
+            let bytes = json.to_bytes(*format).unwrap();
+            assert!(matches!(
+                ChangeJson::from_bytes(&bytes),
+                Err(BundleError::Version(VersionError::Schema { .. }))
+            ));
+        }
+    }
+
+    #[test]
+    fn bundle_without_header_is_refused() {
+        // the layout of bundles written before the header was introduced
+        let text = r#"{
+            "crate_graph": {"crates": [], "deps": []},
+            "local_roots": {"roots": []},
+            "library_roots": {"roots": []},
+            "files_changed": {"files": []}
+        }"#;
+        assert!(matches!(
+            ChangeJson::from_bytes(text.as_bytes()),
+            Err(BundleError::Version(VersionError::Unversioned))
+        ));
+    }
+}
@@ -1,15 +1,30 @@
-use crate::crate_graph_json;
+use crate::{
+    crate_graph_json, proc_macro_json::ExpanderRegistry, subtree_json::SubtreeJson,
+    sysroot::SysrootRefJson, BundleHeader, ChangeError, VersionError,
+};
 use base_db::{Change, FileId, FileSet, SourceRoot, VfsPath};
 use crate_graph_json::CrateGraphJson;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct ChangeJson {
-    crate_graph: Option<CrateGraphJson>,
-    local_roots: Option<SourceRootJson>,
-    library_roots: Option<SourceRootJson>,
-    files_changed: FilesJson,
+    /// Bundles without a header are still loaded by [`ChangeJson::to_change`],
+    /// but refused by [`ChangeJson::from_bytes`] and
+    /// [`ChangeJson::try_to_change`].
+    #[serde(default = "BundleHeader::unversioned")]
+    pub(crate) header: BundleHeader,
+    pub(crate) sysroot: Option<SysrootRefJson>,
+    pub(crate) crate_graph: Option<CrateGraphJson>,
+    /// Recorded proc macro expansions, keyed by [`crate::expansion_key`].
+    #[serde(default)]
+    pub(crate) proc_macro_expansions: Vec<(u64, SubtreeJson)>,
+    pub(crate) local_roots: Option<SourceRootJson>,
+    pub(crate) library_roots: Option<SourceRootJson>,
+    pub(crate) files_changed: FilesJson,
 }
 
 impl ChangeJson {
@@ -25,17 +40,219 @@ impl ChangeJson {
             .map(|roots| SourceRootJson::from(roots, true));
         let files_changed = FilesJson::from(&change.files_changed);
         ChangeJson {
+            header: BundleHeader::current(),
+            sysroot: None,
             crate_graph,
+            proc_macro_expansions: Vec::new(),
             local_roots,
             library_roots,
             files_changed,
         }
     }
 
+    pub fn header(&self) -> &BundleHeader {
+        &self.header
+    }
+
+    pub fn with_header(mut self, header: BundleHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Stores proc macro expansions recorded natively, so that the
+    /// deserialized proc macros can replay them.
+    pub fn set_expansions(&mut self, expansions: Vec<(u64, tt::Subtree)>) {
+        let mut expansions = expansions
+            .iter()
+            .map(|(key, subtree)| (*key, SubtreeJson::from(subtree)))
+            .collect::<Vec<_>>();
+        expansions.sort_by_key(|(key, _)| *key);
+        expansions.dedup_by_key(|(key, _)| *key);
+        self.proc_macro_expansions = expansions;
+    }
+
+    /// Rewrites every file and crate id, e.g. to make room for the ids of
+    /// another bundle.
+    pub(crate) fn map_ids(&mut self, file_id: &dyn Fn(u32) -> u32, crate_id: &dyn Fn(u32) -> u32) {
+        if let Some(sysroot) = self.sysroot.as_mut() {
+            sysroot.map_crate_ids(crate_id);
+        }
+        if let Some(graph) = self.crate_graph.as_mut() {
+            graph.map_ids(file_id, crate_id);
+        }
+        self.local_roots
+            .iter_mut()
+            .chain(self.library_roots.iter_mut())
+            .flat_map(|roots| roots.roots.iter_mut().flatten())
+            .for_each(|(id, _)| *id = file_id(*id));
+        self.files_changed
+            .files
+            .iter_mut()
+            .for_each(|(id, _)| *id = file_id(*id));
+    }
+
+    /// Renumbers files and crates to `0..n`, keeping their relative order.
+    pub(crate) fn compact_ids(&mut self) {
+        let mut file_ids = self
+            .local_roots
+            .iter()
+            .chain(self.library_roots.iter())
+            .flat_map(|roots| roots.roots.iter().flatten().map(|(id, _)| *id))
+            .chain(self.files_changed.files.iter().map(|(id, _)| *id))
+            .chain(
+                self.crate_graph
+                    .iter()
+                    .flat_map(|graph| graph.root_file_ids().map(|(_, file_id)| file_id)),
+            )
+            .collect::<Vec<_>>();
+        file_ids.sort_unstable();
+        file_ids.dedup();
+        let mut crate_ids = self
+            .crate_graph
+            .iter()
+            .flat_map(|graph| graph.root_file_ids().map(|(krate, _)| krate))
+            .collect::<Vec<_>>();
+        crate_ids.sort_unstable();
+        crate_ids.dedup();
+        let index = |ids: &[u32], id: u32| ids.binary_search(&id).map_or(id, |idx| idx as u32);
+        self.map_ids(&|id| index(&file_ids, id), &|id| index(&crate_ids, id));
+    }
+
+    /// Renumbers all files in the order of their paths, so that extracting
+    /// the same set of files always yields the same ids, no matter in which
+    /// order the files were discovered.
+    pub fn stabilize_file_ids(&mut self) {
+        let mut paths = self
+            .local_roots
+            .iter()
+            .chain(self.library_roots.iter())
+            .flat_map(|roots| roots.roots.iter().flatten())
+            .filter_map(|(id, path)| path.as_ref().map(|path| (path.replace('\\', "/"), *id)))
+            .collect::<Vec<_>>();
+        paths.sort();
+        let mut file_ids = HashMap::new();
+        for (_, id) in paths {
+            let next = file_ids.len() as u32;
+            file_ids.entry(id).or_insert(next);
+        }
+        // files without a path keep their relative order
+        let mut unknown = self
+            .local_roots
+            .iter()
+            .chain(self.library_roots.iter())
+            .flat_map(|roots| roots.roots.iter().flatten().map(|(id, _)| *id))
+            .chain(self.files_changed.files.iter().map(|(id, _)| *id))
+            .chain(
+                self.crate_graph
+                    .iter()
+                    .flat_map(|graph| graph.root_file_ids().map(|(_, file_id)| file_id)),
+            )
+            .filter(|id| !file_ids.contains_key(id))
+            .collect::<Vec<_>>();
+        unknown.sort_unstable();
+        for id in unknown {
+            let next = file_ids.len() as u32;
+            file_ids.entry(id).or_insert(next);
+        }
+        self.map_ids(&|id| file_ids.get(&id).copied().unwrap_or(id), &|id| id);
+
+        self.local_roots
+            .iter_mut()
+            .chain(self.library_roots.iter_mut())
+            .flat_map(|roots| roots.roots.iter_mut())
+            .for_each(|root| root.sort());
+        self.files_changed.files.sort_by_key(|(id, _)| *id);
+    }
+
+    /// The highest file id used anywhere in the bundle.
+    pub(crate) fn max_file_id(&self) -> Option<u32> {
+        self.local_roots
+            .iter()
+            .chain(self.library_roots.iter())
+            .flat_map(|roots| roots.roots.iter().flatten().map(|(id, _)| *id))
+            .chain(self.files_changed.files.iter().map(|(id, _)| *id))
+            .chain(
+                self.crate_graph
+                    .iter()
+                    .flat_map(|graph| graph.root_file_ids().map(|(_, file_id)| file_id)),
+            )
+            .max()
+    }
+
+    /// Converts back into a [`Change`], dropping whatever can't be represented.
+    ///
+    /// If the bundle was written for another schema or `ra_ap_*` version, or
+    /// if the serialized crate ids are inconsistent, only the roots and files
+    /// are kept. Problems are logged, use [`ChangeJson::try_to_change`] to get
+    /// them reported instead.
     pub fn to_change(&self) -> Change {
+        self.to_change_with(&ExpanderRegistry::default())
+    }
+
+    /// Like [`ChangeJson::to_change`], attaching the expanders of `registry`
+    /// to the proc macros they were registered for.
+    pub fn to_change_with(&self, registry: &ExpanderRegistry) -> Change {
+        let mut errors = Vec::new();
+        let change = self.build_change(registry, false, &mut errors);
+        for err in errors {
+            log::warn!("{}", err);
+        }
+        change
+    }
+
+    /// Converts back into a [`Change`], together with the problems which were
+    /// worked around, e.g. dropped dependencies. Fails with every problem
+    /// found if one of them is [fatal](ChangeError::is_fatal).
+    pub fn try_to_change(&self) -> Result<(Change, Vec<ChangeError>), Vec<ChangeError>> {
+        self.try_to_change_with(&ExpanderRegistry::default())
+    }
+
+    /// Like [`ChangeJson::try_to_change`], attaching the expanders of
+    /// `registry` to the proc macros they were registered for.
+    pub fn try_to_change_with(
+        &self,
+        registry: &ExpanderRegistry,
+    ) -> Result<(Change, Vec<ChangeError>), Vec<ChangeError>> {
+        let mut errors = Vec::new();
+        let change = self.build_change(registry, true, &mut errors);
+        if errors.iter().any(ChangeError::is_fatal) {
+            Err(errors)
+        } else {
+            Ok((change, errors))
+        }
+    }
+
+    /// Unless `strict`, bundles without a header are loaded as if they had
+    /// the current version.
+    fn build_change(
+        &self,
+        registry: &ExpanderRegistry,
+        strict: bool,
+        errors: &mut Vec<ChangeError>,
+    ) -> Change {
         let mut change = Change::default();
-        if let Some(graph) = self.crate_graph.as_ref() {
-            change.set_crate_graph(graph.to_crate_graph())
+        // the crate graph of another version can't be trusted, but its files can
+        let graph = match self.header.check() {
+            Ok(()) => self.crate_graph.as_ref(),
+            Err(err) => {
+                let migrate = !strict && err == VersionError::Unversioned;
+                errors.push(err.into());
+                self.crate_graph.as_ref().filter(|_| migrate)
+            }
+        };
+        if self.sysroot.is_some() {
+            errors.push(ChangeError::UncomposedSysroot);
+        }
+        if let Some(graph) = graph {
+            let expansions = self
+                .proc_macro_expansions
+                .iter()
+                .map(|(key, subtree)| (*key, subtree.to_subtree()))
+                .collect();
+            match graph.to_crate_graph(registry, &Arc::new(expansions), errors) {
+                Ok(graph) => change.set_crate_graph(graph),
+                Err(err) => errors.push(err.into()),
+            }
         }
         let mut roots = Vec::new();
         if let Some(local) = self.local_roots.as_ref() {
@@ -44,7 +261,11 @@ impl ChangeJson {
         if let Some(library) = self.library_roots.as_ref() {
             roots.append(&mut library.to_roots(true))
         }
-        change.set_roots(roots.to_vec());
+        self.check_file_ids(errors);
+        // a delta without roots must keep the roots already in the database
+        if self.local_roots.is_some() || self.library_roots.is_some() {
+            change.set_roots(roots.to_vec());
+        }
         self.files_changed.files.iter().for_each(|(id, text)| {
             let id = FileId(*id);
             let text = text.as_ref().map(|text| Arc::new(text.to_string()));
@@ -52,11 +273,37 @@ impl ChangeJson {
         });
         change
     }
+
+    /// Checks that crate roots and changed files are part of a source root.
+    fn check_file_ids(&self, errors: &mut Vec<ChangeError>) {
+        if self.local_roots.is_none() && self.library_roots.is_none() {
+            return;
+        }
+        let known = self
+            .local_roots
+            .iter()
+            .chain(self.library_roots.iter())
+            .flat_map(SourceRootJson::file_ids)
+            .collect::<HashSet<_>>();
+        if let Some(graph) = self.crate_graph.as_ref() {
+            graph
+                .root_file_ids()
+                .filter(|(_, file_id)| !known.contains(file_id))
+                .for_each(|(krate, file_id)| {
+                    errors.push(ChangeError::DanglingRootFile { krate, file_id })
+                });
+        }
+        self.files_changed
+            .files
+            .iter()
+            .filter(|(file_id, _)| !known.contains(file_id))
+            .for_each(|(file_id, _)| errors.push(ChangeError::DanglingFile { file_id: *file_id }));
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-struct SourceRootJson {
-    roots: Vec<Vec<(u32, Option<String>)>>,
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub(crate) struct SourceRootJson {
+    pub(crate) roots: Vec<Vec<(u32, Option<String>)>>,
 }
 
 impl SourceRootJson {
@@ -77,6 +324,15 @@ impl SourceRootJson {
             .collect::<Vec<Vec<(u32, Option<String>)>>>();
         SourceRootJson { roots }
     }
+    /// Ids of all files which end up in a source root.
+    fn file_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.roots
+            .iter()
+            .flatten()
+            .filter(|(_, path)| path.is_some())
+            .map(|(file_id, _)| *file_id)
+    }
+
     fn to_roots(&self, library: bool) -> Vec<SourceRoot> {
         let result = self
             .roots
@@ -107,9 +363,9 @@ impl SourceRootJson {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-struct FilesJson {
-    files: Vec<(u32, Option<String>)>,
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub(crate) struct FilesJson {
+    pub(crate) files: Vec<(u32, Option<String>)>,
 }
 
 impl FilesJson {
@@ -121,3 +377,132 @@ impl FilesJson {
         FilesJson { files }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SCHEMA_VERSION;
+
+    fn roots(files: &[(u32, &str)]) -> Option<SourceRootJson> {
+        let root = files
+            .iter()
+            .map(|(id, path)| (*id, Some(path.to_string())))
+            .collect();
+        Some(SourceRootJson { roots: vec![root] })
+    }
+
+    fn files(ids: &[u32]) -> FilesJson {
+        let files = ids
+            .iter()
+            .map(|id| (*id, Some(format!("// file {}", id))))
+            .collect();
+        FilesJson { files }
+    }
+
+    #[test]
+    fn stable_file_ids_follow_paths() {
+        let mut first = ChangeJson {
+            local_roots: roots(&[(0, "/p/src/main.rs"), (1, "/p/src/lib.rs")]),
+            library_roots: roots(&[(2, "/dep/lib.rs")]),
+            files_changed: files(&[0, 1, 2]),
+            ..ChangeJson::default()
+        };
+        let mut second = ChangeJson {
+            local_roots: roots(&[(2, "/p/src/lib.rs"), (0, "/p/src/main.rs")]),
+            library_roots: roots(&[(1, "/dep/lib.rs")]),
+            files_changed: files(&[2, 0, 1]),
+            ..ChangeJson::default()
+        };
+        first.stabilize_file_ids();
+        second.stabilize_file_ids();
+        let library = |json: &ChangeJson| json.library_roots.clone().unwrap().roots;
+        let local = |json: &ChangeJson| json.local_roots.clone().unwrap().roots;
+        assert_eq!(
+            library(&first),
+            vec![vec![(0, Some("/dep/lib.rs".to_string()))]]
+        );
+        assert_eq!(local(&first), local(&second));
+        assert_eq!(library(&first), library(&second));
+        let ids = |json: &ChangeJson| {
+            json.files_changed
+                .files
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&first), vec![0, 1, 2]);
+        assert_eq!(ids(&second), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn stable_file_ids_keep_files_without_path() {
+        let mut json = ChangeJson {
+            local_roots: Some(SourceRootJson {
+                roots: vec![vec![(5, None), (3, Some("/p/src/lib.rs".to_string()))]],
+            }),
+            files_changed: files(&[5, 3]),
+            ..ChangeJson::default()
+        };
+        json.stabilize_file_ids();
+        assert_eq!(
+            json.local_roots.unwrap().roots,
+            vec![vec![(0, Some("/p/src/lib.rs".to_string())), (1, None)]]
+        );
+    }
+
+    #[test]
+    fn warnings_keep_the_change() {
+        let json = ChangeJson {
+            local_roots: roots(&[(0, "/p/src/lib.rs")]),
+            files_changed: files(&[0, 1]),
+            ..ChangeJson::default()
+        };
+        let (change, warnings) = json.try_to_change().unwrap();
+        assert_eq!(warnings, vec![ChangeError::DanglingFile { file_id: 1 }]);
+        assert_eq!(change.files_changed.len(), 2);
+
+        let json = json.with_header(BundleHeader {
+            schema_version: 0,
+            ..BundleHeader::current()
+        });
+        let errors = json.try_to_change().unwrap_err();
+        assert!(errors[0].is_fatal());
+    }
+
+    #[test]
+    fn fatal_errors_keep_roots_and_files() {
+        let json = ChangeJson {
+            header: BundleHeader {
+                schema_version: SCHEMA_VERSION + 1,
+                ..BundleHeader::current()
+            },
+            crate_graph: Some(CrateGraphJson::default()),
+            local_roots: roots(&[(0, "/p/src/lib.rs")]),
+            files_changed: files(&[0]),
+            ..ChangeJson::default()
+        };
+        assert!(json.try_to_change().is_err());
+        let change = json.to_change();
+        assert!(change.crate_graph.is_none());
+        assert_eq!(change.roots.map(|roots| roots.len()), Some(1));
+        assert_eq!(change.files_changed.len(), 1);
+    }
+
+    #[test]
+    fn bundle_without_header_is_loaded_by_to_change() {
+        let text = r#"{
+            "crate_graph": {"crates": [], "deps": []},
+            "local_roots": {"roots": [[[0, "/p/src/lib.rs"]]]},
+            "library_roots": {"roots": []},
+            "files_changed": {"files": [[0, "fn main() {}"]]}
+        }"#;
+        let json = serde_json::from_str::<ChangeJson>(text).unwrap();
+        assert_eq!(
+            json.try_to_change().unwrap_err(),
+            vec![ChangeError::Version(VersionError::Unversioned)]
+        );
+        let change = json.to_change();
+        assert!(change.crate_graph.is_some());
+        assert_eq!(change.files_changed.len(), 1);
+    }
+}
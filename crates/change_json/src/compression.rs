@@ -0,0 +1,104 @@
+use std::{
+    io::{self, BufRead, Read},
+    path::Path,
+};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression applied on top of a bundle in any [`BundleFormat`].
+///
+/// [`BundleFormat`]: crate::BundleFormat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn detect(bytes: &[u8]) -> Compression {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Picks the compression from the extension, e.g. `change.json.gz`.
+    pub fn from_path(path: &Path) -> Compression {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression `{}`", s)),
+        }
+    }
+}
+
+/// Wraps `reader` into a streaming decoder, detecting the compression from
+/// the first bytes of the stream.
+pub fn decompress<'a, R: BufRead + 'a>(mut reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let compression = Compression::detect(reader.fill_buf()?);
+    let reader: Box<dyn Read + 'a> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Zstd => {
+            let decoder = ruzstd::StreamingDecoder::new(reader)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+            Box::new(decoder)
+        }
+    };
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression as Level};
+    use std::io::Write;
+
+    #[test]
+    fn decompress_detects_compression() {
+        let text = b"{\"header\":{}}";
+        let mut plain = Vec::new();
+        decompress(&text[..])
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plain, text);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(text).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(Compression::detect(&compressed), Compression::Gzip);
+        let mut plain = Vec::new();
+        decompress(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plain, text);
+    }
+
+    #[test]
+    fn compression_from_path() {
+        let compression = |path: &str| Compression::from_path(Path::new(path));
+        assert_eq!(compression("change.json"), Compression::None);
+        assert_eq!(compression("change.json.gz"), Compression::Gzip);
+        assert_eq!(compression("change.bin.zst"), Compression::Zstd);
+    }
+}
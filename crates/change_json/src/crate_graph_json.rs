@@ -1,43 +1,75 @@
+use crate::{
+    proc_macro_json::{ExpanderRegistry, Expansions, ProcMacroJson},
+    ChangeError,
+};
 use base_db::{CrateData, CrateDisplayName, CrateGraph, CrateId, CrateName, Edition, Env, FileId};
-use cfg::CfgOptions;
+use cfg::{CfgAtom, CfgExpr, CfgOptions};
 use serde::{Deserialize, Serialize};
-use std::ops::Index;
+use std::{collections::HashMap, fmt, ops::Index, sync::Arc};
 use tt::SmolStr;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub(crate) struct CrateGraphJson {
-    crates: Vec<(u32, CrateDataJson)>,
-    deps: Vec<DepJson>,
+    pub(crate) crates: Vec<(u32, CrateDataJson)>,
+    pub(crate) deps: Vec<DepJson>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
-struct CrateDataJson {
-    root_file_id: u32,
-    edition: String,
-    display_name: Option<String>,
+pub(crate) struct CrateDataJson {
+    pub(crate) root_file_id: u32,
+    pub(crate) edition: String,
+    pub(crate) display_name: Option<String>,
     cfg_options: CfgOptionsJson,
     potential_cfg_options: CfgOptionsJson,
-    env: EnvJson,
-    proc_macro: Vec<String>,
+    pub(crate) env: EnvJson,
+    proc_macro: Vec<ProcMacroJson>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 struct CfgOptionsJson {
-    options: Vec<(String, Vec<String>)>,
+    flags: Vec<String>,
+    key_values: Vec<(String, String)>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
-struct EnvJson {
-    env: Vec<(String, String)>,
+pub(crate) struct EnvJson {
+    pub(crate) env: Vec<(String, String)>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
-struct DepJson {
-    from: u32,
-    name: String,
-    to: u32,
+pub(crate) struct DepJson {
+    pub(crate) from: u32,
+    pub(crate) name: String,
+    pub(crate) to: u32,
 }
 
+/// The serialized crate ids don't form the contiguous range `0..n`, or a
+/// dependency refers to a crate which isn't part of the graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrateIdError {
+    Duplicate(u32),
+    Missing(u32),
+    UnknownDependency { from: u32, to: u32 },
+}
+
+impl fmt::Display for CrateIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrateIdError::Duplicate(id) => write!(f, "crate id {} is used more than once", id),
+            CrateIdError::Missing(id) => write!(f, "crate id {} is missing", id),
+            CrateIdError::UnknownDependency { from, to } => {
+                write!(
+                    f,
+                    "dependency {} -> {} refers to an unknown crate",
+                    from, to
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CrateIdError {}
+
 impl CrateGraphJson {
     pub(crate) fn from(crate_graph: &CrateGraph) -> Self {
         let mut deps: Vec<DepJson> = Vec::new();
@@ -64,11 +96,66 @@ impl CrateGraphJson {
         CrateGraphJson { crates, deps }
     }
 
-    pub(crate) fn to_crate_graph(&self) -> CrateGraph {
+    /// Checks that the serialized ids are exactly `0..n`, each used once.
+    fn check_ids(&self) -> Result<(), CrateIdError> {
+        let mut ids = self.crates.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        ids.sort_unstable();
+        for (expected, pair) in (0u32..).zip(ids.windows(2)) {
+            if pair[0] == pair[1] {
+                return Err(CrateIdError::Duplicate(pair[0]));
+            }
+            if pair[0] != expected {
+                return Err(CrateIdError::Missing(expected));
+            }
+        }
+        match ids.last() {
+            Some(last) if *last as usize != ids.len() - 1 => {
+                Err(CrateIdError::Missing(ids.len() as u32 - 1))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn map_ids(&mut self, file_id: &dyn Fn(u32) -> u32, crate_id: &dyn Fn(u32) -> u32) {
+        self.crates.iter_mut().for_each(|(id, data)| {
+            *id = crate_id(*id);
+            data.root_file_id = file_id(data.root_file_id);
+        });
+        self.deps.iter_mut().for_each(|dep| {
+            dep.from = crate_id(dep.from);
+            dep.to = crate_id(dep.to);
+        });
+    }
+
+    /// Serialized crate ids together with the id of their root file.
+    pub(crate) fn root_file_ids(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.crates
+            .iter()
+            .map(|(id, data)| (*id, data.root_file_id))
+    }
+
+    /// Builds the crate graph, pushing every recoverable problem to `errors`.
+    ///
+    /// Proc macros use the expanders of `registry`, or else replay the
+    /// recorded `expansions`.
+    pub(crate) fn to_crate_graph(
+        &self,
+        registry: &ExpanderRegistry,
+        expansions: &Arc<Expansions>,
+        errors: &mut Vec<ChangeError>,
+    ) -> Result<CrateGraph, CrateIdError> {
+        self.check_ids()?;
         let mut crate_graph = CrateGraph::default();
-        self.crates.iter().for_each(|(_, data)| {
+        let mut crate_ids: HashMap<u32, CrateId> = HashMap::new();
+        self.crates.iter().for_each(|(id, data)| {
             let file_id = FileId(data.root_file_id);
-            let edition = data.edition.parse::<Edition>().unwrap_or(Edition::CURRENT);
+            let edition = data.edition.parse::<Edition>().unwrap_or_else(|_| {
+                errors.push(ChangeError::UnknownEdition {
+                    krate: *id,
+                    edition: data.edition.clone(),
+                });
+                Edition::CURRENT
+            });
             let display_name = data
                 .display_name
                 .as_ref()
@@ -76,24 +163,51 @@ impl CrateGraphJson {
             let cfg_options = data.cfg_options.to_cfg_options();
             let potential_cfg_options = data.potential_cfg_options.to_cfg_options();
             let env = data.env.to_env();
-            crate_graph.add_crate_root(
+            let proc_macro = data
+                .proc_macro
+                .iter()
+                .map(|proc_macro| {
+                    proc_macro.to_proc_macro(data.display_name.as_deref(), registry, expansions)
+                })
+                .collect::<Vec<_>>();
+            let crate_id = crate_graph.add_crate_root(
                 file_id,
                 edition,
                 display_name,
                 cfg_options,
                 potential_cfg_options,
                 env,
-                Vec::new(),
+                proc_macro,
             );
+            crate_ids.insert(*id, crate_id);
         });
-        self.deps.iter().for_each(|dep| {
-            let from = CrateId(dep.from);
-            let to = CrateId(dep.to);
-            if let Ok(name) = CrateName::new(&dep.name) {
-                let _ = crate_graph.add_dep(from, name, to);
+        for dep in self.deps.iter() {
+            let (from, to) = match (crate_ids.get(&dep.from), crate_ids.get(&dep.to)) {
+                (Some(from), Some(to)) => (*from, *to),
+                _ => {
+                    return Err(CrateIdError::UnknownDependency {
+                        from: dep.from,
+                        to: dep.to,
+                    })
+                }
             };
-        });
-        crate_graph
+            match CrateName::new(&dep.name) {
+                Ok(name) => {
+                    if crate_graph.add_dep(from, name, to).is_err() {
+                        errors.push(ChangeError::CyclicDependency {
+                            from: dep.from,
+                            name: dep.name.clone(),
+                            to: dep.to,
+                        })
+                    }
+                }
+                Err(_) => errors.push(ChangeError::InvalidCrateName {
+                    from: dep.from,
+                    name: dep.name.clone(),
+                }),
+            }
+        }
+        Ok(crate_graph)
     }
 }
 
@@ -108,7 +222,11 @@ impl CrateDataJson {
         let cfg_options = CfgOptionsJson::from(&crate_data.cfg_options);
         let potential_cfg_options = CfgOptionsJson::from(&crate_data.potential_cfg_options);
         let env = EnvJson::from(crate_data.env.clone());
-        let proc_macro = Vec::new();
+        let proc_macro = crate_data
+            .proc_macro
+            .iter()
+            .map(ProcMacroJson::from)
+            .collect::<Vec<_>>();
         CrateDataJson {
             root_file_id,
             edition,
@@ -123,31 +241,38 @@ impl CrateDataJson {
 
 impl CfgOptionsJson {
     fn from(cfg_options: &CfgOptions) -> Self {
-        let options = cfg_options
-            .get_cfg_keys()
-            .iter()
-            .map(|key| {
-                (
-                    String::from(key.as_str()),
-                    cfg_options
-                        .get_cfg_values(key)
-                        .iter()
-                        .map(|val| String::from(val.as_str()))
-                        .collect::<Vec<_>>(),
-                )
-            })
-            .collect::<Vec<_>>();
-        CfgOptionsJson { options }
+        let mut keys = cfg_options.get_cfg_keys();
+        keys.sort();
+        keys.dedup();
+        let mut flags = Vec::new();
+        let mut key_values = Vec::new();
+        for key in keys {
+            // `get_cfg_keys` lists the keys of flags and key-value atoms alike,
+            // so ask whether the plain flag is enabled as well.
+            let flag = CfgExpr::Atom(CfgAtom::Flag(key.clone()));
+            if cfg_options.check(&flag) == Some(true) {
+                flags.push(key.to_string());
+            }
+            let mut values = cfg_options.get_cfg_values(key);
+            values.sort();
+            key_values.extend(
+                values
+                    .into_iter()
+                    .map(|value| (key.to_string(), value.to_string())),
+            );
+        }
+        CfgOptionsJson { flags, key_values }
     }
 
     fn to_cfg_options(&self) -> CfgOptions {
         let mut cfg_options = CfgOptions::default();
-        self.options.iter().for_each(|(key, values)| {
-            values.iter().for_each(|value| {
-                let key = SmolStr::from(key);
-                let value = SmolStr::from(value);
-                cfg_options.insert_key_value(key, value);
-            })
+        self.flags
+            .iter()
+            .for_each(|flag| cfg_options.insert_atom(SmolStr::from(flag)));
+        self.key_values.iter().for_each(|(key, value)| {
+            let key = SmolStr::from(key);
+            let value = SmolStr::from(value);
+            cfg_options.insert_key_value(key, value);
         });
         cfg_options
     }
@@ -174,6 +299,8 @@ impl EnvJson {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::proc_macro_json::ReplayExpander;
+    use base_db::{ProcMacro, ProcMacroKind};
 
     #[test]
     fn serialize_crategraph_check_deps() {
@@ -225,6 +352,174 @@ mod tests {
             },
         ];
         assert_eq!(serialized_graph.deps, expected_deps);
-        serialized_graph.to_crate_graph();
+        serialized_graph
+            .to_crate_graph(&Default::default(), &Default::default(), &mut Vec::new())
+            .unwrap();
+    }
+
+    #[test]
+    fn deserialize_crategraph_remaps_ids() {
+        let crate_data = |root_file_id| CrateDataJson {
+            root_file_id,
+            edition: "2018".to_string(),
+            ..Default::default()
+        };
+        let mut serialized_graph = CrateGraphJson {
+            crates: vec![(1, crate_data(2)), (0, crate_data(1))],
+            deps: vec![DepJson {
+                from: 0,
+                name: "crate2".to_string(),
+                to: 1,
+            }],
+        };
+        let graph = serialized_graph
+            .to_crate_graph(&Default::default(), &Default::default(), &mut Vec::new())
+            .unwrap();
+        let krate = graph
+            .iter()
+            .find(|id| graph[*id].root_file_id == FileId(1))
+            .unwrap();
+        let dep = &graph[krate].dependencies[0];
+        assert_eq!(graph[dep.crate_id].root_file_id, FileId(2));
+
+        serialized_graph.crates.push((1, crate_data(3)));
+        assert_eq!(
+            serialized_graph
+                .to_crate_graph(&Default::default(), &Default::default(), &mut Vec::new())
+                .unwrap_err(),
+            CrateIdError::Duplicate(1)
+        );
+        serialized_graph.crates[2].0 = 3;
+        assert_eq!(
+            serialized_graph
+                .to_crate_graph(&Default::default(), &Default::default(), &mut Vec::new())
+                .unwrap_err(),
+            CrateIdError::Missing(2)
+        );
+        serialized_graph.crates.pop();
+        serialized_graph.deps[0].to = 5;
+        assert_eq!(
+            serialized_graph
+                .to_crate_graph(&Default::default(), &Default::default(), &mut Vec::new())
+                .unwrap_err(),
+            CrateIdError::UnknownDependency { from: 0, to: 5 }
+        );
+    }
+
+    #[test]
+    fn deserialize_crategraph_reports_errors() {
+        let crate_data = |root_file_id, edition: &str| CrateDataJson {
+            root_file_id,
+            edition: edition.to_string(),
+            ..Default::default()
+        };
+        let dep = |from, name: &str, to| DepJson {
+            from,
+            name: name.to_string(),
+            to,
+        };
+        let serialized_graph = CrateGraphJson {
+            crates: vec![(0, crate_data(1, "2018")), (1, crate_data(2, "2042"))],
+            deps: vec![
+                dep(0, "crate2", 1),
+                dep(1, "crate1", 0),
+                dep(0, "not-a-name", 1),
+            ],
+        };
+        let mut errors = Vec::new();
+        let graph = serialized_graph
+            .to_crate_graph(&Default::default(), &Default::default(), &mut errors)
+            .unwrap();
+        assert_eq!(
+            errors,
+            vec![
+                ChangeError::UnknownEdition {
+                    krate: 1,
+                    edition: "2042".to_string()
+                },
+                ChangeError::CyclicDependency {
+                    from: 1,
+                    name: "crate1".to_string(),
+                    to: 0
+                },
+                ChangeError::InvalidCrateName {
+                    from: 0,
+                    name: "not-a-name".to_string()
+                },
+            ]
+        );
+        let krate = graph
+            .iter()
+            .find(|id| graph[*id].root_file_id == FileId(1))
+            .unwrap();
+        assert_eq!(graph[krate].dependencies.len(), 1);
+    }
+
+    #[test]
+    fn serialize_crategraph_check_proc_macros() {
+        let mut graph = CrateGraph::default();
+        let proc_macro = ProcMacroJson::from(&ProcMacro {
+            name: "Serialize".into(),
+            kind: ProcMacroKind::CustomDerive,
+            expander: Arc::new(ReplayExpander::new("Serialize".into(), Default::default())),
+        });
+        graph.add_crate_root(
+            FileId(1u32),
+            Edition::Edition2018,
+            None,
+            CfgOptions::default(),
+            CfgOptions::default(),
+            Env::default(),
+            vec![proc_macro.to_proc_macro(None, &Default::default(), &Default::default())],
+        );
+        let serialized_graph = CrateGraphJson::from(&graph);
+        assert_eq!(serialized_graph.crates[0].1.proc_macro, vec![proc_macro]);
+        let graph = serialized_graph
+            .to_crate_graph(&Default::default(), &Default::default(), &mut Vec::new())
+            .unwrap();
+        let krate = graph.iter().next().unwrap();
+        let proc_macros = &graph[krate].proc_macro;
+        assert_eq!(proc_macros.len(), 1);
+        assert_eq!(proc_macros[0].name, "Serialize");
+        assert_eq!(proc_macros[0].kind, ProcMacroKind::CustomDerive);
+    }
+
+    #[test]
+    fn serialize_crategraph_check_cfg_options() {
+        let mut cfg_options = CfgOptions::default();
+        cfg_options.insert_atom("test".into());
+        cfg_options.insert_atom("unix".into());
+        cfg_options.insert_atom("debug_assertions".into());
+        cfg_options.insert_key_value("target_os".into(), "linux".into());
+        cfg_options.insert_key_value("feature".into(), "default".into());
+        cfg_options.insert_key_value("feature".into(), "std".into());
+        // a key which is used both as flag and as key-value atom
+        cfg_options.insert_atom("panic".into());
+        cfg_options.insert_key_value("panic".into(), "unwind".into());
+        let mut potential_cfg_options = cfg_options.clone();
+        potential_cfg_options.insert_key_value("feature".into(), "alloc".into());
+
+        let mut graph = CrateGraph::default();
+        graph.add_crate_root(
+            FileId(1u32),
+            Edition::Edition2018,
+            None,
+            cfg_options.clone(),
+            potential_cfg_options.clone(),
+            Env::default(),
+            Default::default(),
+        );
+        let serialized_graph = CrateGraphJson::from(&graph);
+        let crate_data = &serialized_graph.crates[0].1;
+        assert_eq!(
+            crate_data.cfg_options.flags,
+            vec!["debug_assertions", "panic", "test", "unix"]
+        );
+        let graph = serialized_graph
+            .to_crate_graph(&Default::default(), &Default::default(), &mut Vec::new())
+            .unwrap();
+        let krate = graph.iter().next().unwrap();
+        assert_eq!(graph[krate].cfg_options, cfg_options);
+        assert_eq!(graph[krate].potential_cfg_options, potential_cfg_options);
     }
 }
@@ -0,0 +1,153 @@
+use crate::{change_json::FilesJson, ChangeJson};
+use std::collections::HashMap;
+
+impl ChangeJson {
+    /// Computes the smallest bundle which turns a database loaded from `old`
+    /// into one loaded from `new`, when applied with [`ChangeJson::to_change`].
+    ///
+    /// Both bundles need to number their files the same way, e.g. because
+    /// they were extracted with stable file ids, and must not refer to a
+    /// separate sysroot bundle.
+    pub fn diff(old: &ChangeJson, new: &ChangeJson) -> ChangeJson {
+        // expansions are only replayed by proc macros of a new crate graph
+        let graph_changed = old.crate_graph != new.crate_graph
+            || old.proc_macro_expansions != new.proc_macro_expansions;
+        let (crate_graph, proc_macro_expansions) = if graph_changed {
+            (new.crate_graph.clone(), new.proc_macro_expansions.clone())
+        } else {
+            (None, Vec::new())
+        };
+        // roots are always replaced as a whole
+        let roots_changed =
+            old.local_roots != new.local_roots || old.library_roots != new.library_roots;
+        let (local_roots, library_roots) = if roots_changed {
+            (new.local_roots.clone(), new.library_roots.clone())
+        } else {
+            (None, None)
+        };
+
+        let old_files = old
+            .files_changed
+            .files
+            .iter()
+            .map(|(id, text)| (*id, text))
+            .collect::<HashMap<_, _>>();
+        let new_files = new
+            .files_changed
+            .files
+            .iter()
+            .map(|(id, text)| (*id, text))
+            .collect::<HashMap<_, _>>();
+        let mut files = new
+            .files_changed
+            .files
+            .iter()
+            .filter(|(id, text)| old_files.get(id) != Some(&text))
+            .cloned()
+            .chain(
+                old.files_changed
+                    .files
+                    .iter()
+                    .filter(|(id, text)| text.is_some() && !new_files.contains_key(id))
+                    .map(|(id, _)| (*id, None)),
+            )
+            .collect::<Vec<_>>();
+        files.sort_by_key(|(id, _)| *id);
+
+        ChangeJson {
+            header: new.header.clone(),
+            sysroot: None,
+            crate_graph,
+            proc_macro_expansions,
+            local_roots,
+            library_roots,
+            files_changed: FilesJson { files },
+        }
+    }
+
+    /// Applies a bundle created by [`ChangeJson::diff`], so that `self`
+    /// becomes the bundle the delta was computed against.
+    pub fn apply(&mut self, delta: &ChangeJson) {
+        self.header = delta.header.clone();
+        if delta.crate_graph.is_some() {
+            self.crate_graph = delta.crate_graph.clone();
+            self.proc_macro_expansions = delta.proc_macro_expansions.clone();
+        }
+        if delta.local_roots.is_some() || delta.library_roots.is_some() {
+            self.local_roots = delta.local_roots.clone();
+            self.library_roots = delta.library_roots.clone();
+        }
+        let files = &mut self.files_changed.files;
+        for (id, text) in delta.files_changed.files.iter() {
+            match (files.iter().position(|(file_id, _)| file_id == id), text) {
+                (Some(idx), Some(_)) => files[idx].1 = text.clone(),
+                (Some(idx), None) => {
+                    files.remove(idx);
+                }
+                (None, Some(_)) => files.push((*id, text.clone())),
+                (None, None) => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{change_json::SourceRootJson, crate_graph_json::CrateGraphJson};
+
+    use super::*;
+
+    fn bundle(files: &[(u32, &str, &str)]) -> ChangeJson {
+        let root = files
+            .iter()
+            .map(|(id, path, _)| (*id, Some(path.to_string())))
+            .collect();
+        let files = files
+            .iter()
+            .map(|(id, _, text)| (*id, Some(text.to_string())))
+            .collect();
+        ChangeJson {
+            crate_graph: Some(CrateGraphJson::default()),
+            local_roots: Some(SourceRootJson { roots: vec![root] }),
+            library_roots: Some(SourceRootJson::default()),
+            files_changed: FilesJson { files },
+            ..ChangeJson::default()
+        }
+    }
+
+    #[test]
+    fn diff_contains_only_changed_files() {
+        let old = bundle(&[(0, "/p/lib.rs", "mod a;"), (1, "/p/a.rs", "fn a() {}")]);
+        let new = bundle(&[(0, "/p/lib.rs", "mod a;"), (1, "/p/a.rs", "fn b() {}")]);
+        let delta = ChangeJson::diff(&old, &new);
+        assert_eq!(delta.crate_graph, None);
+        assert_eq!(delta.local_roots, None);
+        assert_eq!(
+            delta.files_changed.files,
+            vec![(1, Some("fn b() {}".to_string()))]
+        );
+        let mut applied = old.clone();
+        applied.apply(&delta);
+        assert_eq!(applied, new);
+    }
+
+    #[test]
+    fn diff_contains_roots_of_added_and_removed_files() {
+        let old = bundle(&[(0, "/p/lib.rs", "mod a;"), (1, "/p/a.rs", "")]);
+        let new = bundle(&[(0, "/p/lib.rs", "mod b;"), (2, "/p/b.rs", "")]);
+        let delta = ChangeJson::diff(&old, &new);
+        assert_eq!(delta.local_roots, new.local_roots);
+        assert_eq!(
+            delta.files_changed.files,
+            vec![
+                (0, Some("mod b;".to_string())),
+                (1, None),
+                (2, Some(String::new()))
+            ]
+        );
+        let mut applied = old.clone();
+        applied.apply(&delta);
+        assert_eq!(applied, new);
+        assert_eq!(delta.try_to_change().unwrap().1, vec![]);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{CrateIdError, VersionError};
+use std::fmt;
+
+/// A problem found while turning a [`ChangeJson`] into a [`Change`].
+///
+/// [`ChangeJson`]: crate::ChangeJson
+/// [`Change`]: base_db::Change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeError {
+    /// The bundle was written for an incompatible schema or `ra_ap_*` version.
+    Version(VersionError),
+    /// The serialized crate ids are inconsistent, no crate graph can be built.
+    CrateId(CrateIdError),
+    /// A crate's root file is not part of any source root.
+    DanglingRootFile { krate: u32, file_id: u32 },
+    /// A changed file is not part of any source root.
+    DanglingFile { file_id: u32 },
+    /// A dependency is not a valid crate name, the dependency was dropped.
+    InvalidCrateName { from: u32, name: String },
+    /// A dependency would close a cycle, the dependency was dropped.
+    CyclicDependency { from: u32, name: String, to: u32 },
+    /// The edition of a crate is unknown, `Edition::CURRENT` was used instead.
+    UnknownEdition { krate: u32, edition: String },
+    /// The bundle refers to a separate sysroot bundle, so the dependencies on
+    /// the sysroot crates were dropped. See [`ChangeJson::compose`].
+    ///
+    /// [`ChangeJson::compose`]: crate::ChangeJson::compose
+    UncomposedSysroot,
+}
+
+impl ChangeError {
+    /// Whether the error prevents building a meaningful [`Change`] at all.
+    ///
+    /// [`Change`]: base_db::Change
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ChangeError::Version(_) | ChangeError::CrateId(_))
+    }
+}
+
+impl fmt::Display for ChangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeError::Version(err) => write!(f, "incompatible bundle: {}", err),
+            ChangeError::CrateId(err) => write!(f, "invalid crate graph: {}", err),
+            ChangeError::DanglingRootFile { krate, file_id } => write!(
+                f,
+                "root file {} of crate {} is not part of any source root",
+                file_id, krate
+            ),
+            ChangeError::DanglingFile { file_id } => {
+                write!(f, "file {} is not part of any source root", file_id)
+            }
+            ChangeError::InvalidCrateName { from, name } => write!(
+                f,
+                "dependency `{}` of crate {} is not a valid crate name",
+                name, from
+            ),
+            ChangeError::CyclicDependency { from, name, to } => write!(
+                f,
+                "dependency `{}` from crate {} to crate {} creates a cycle",
+                name, from, to
+            ),
+            ChangeError::UnknownEdition { krate, edition } => {
+                write!(f, "crate {} has unknown edition `{}`", krate, edition)
+            }
+            ChangeError::UncomposedSysroot => write!(
+                f,
+                "bundle refers to a sysroot bundle, compose them to keep the sysroot dependencies"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChangeError {}
+
+impl From<VersionError> for ChangeError {
+    fn from(err: VersionError) -> Self {
+        ChangeError::Version(err)
+    }
+}
+
+impl From<CrateIdError> for ChangeError {
+    fn from(err: CrateIdError) -> Self {
+        ChangeError::CrateId(err)
+    }
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the bundle layout, bumped on every incompatible change.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Version of the `ra_ap_*` crates this crate is built against, keep in sync
+/// with `Cargo.toml`.
+pub const RA_AP_VERSION: &str = "0.0.72";
+
+/// Describes which layout a bundle has and who produced it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BundleHeader {
+    pub schema_version: u32,
+    pub ra_ap_version: String,
+    pub extractor_version: Option<String>,
+    pub rustc_version: Option<String>,
+    /// Seconds since the unix epoch.
+    pub created_at: Option<u64>,
+}
+
+impl BundleHeader {
+    /// Header of a bundle written by this version of `change_json`, without
+    /// any producer metadata.
+    pub fn current() -> Self {
+        BundleHeader {
+            schema_version: SCHEMA_VERSION,
+            ra_ap_version: RA_AP_VERSION.to_string(),
+            extractor_version: None,
+            rustc_version: None,
+            created_at: None,
+        }
+    }
+
+    /// Stands in for the header of bundles written before headers existed,
+    /// so that they are refused with [`VersionError::Unversioned`].
+    pub(crate) fn unversioned() -> Self {
+        BundleHeader {
+            schema_version: 0,
+            ra_ap_version: String::new(),
+            extractor_version: None,
+            rustc_version: None,
+            created_at: None,
+        }
+    }
+
+    pub fn new(extractor_version: &str, rustc_version: Option<String>, created_at: u64) -> Self {
+        BundleHeader {
+            extractor_version: Some(extractor_version.to_string()),
+            rustc_version,
+            created_at: Some(created_at),
+            ..BundleHeader::current()
+        }
+    }
+
+    /// Checks whether a bundle with this header can be read by this version
+    /// of `change_json`.
+    pub fn check(&self) -> Result<(), VersionError> {
+        if self.schema_version == 0 {
+            return Err(VersionError::Unversioned);
+        }
+        if self.schema_version != SCHEMA_VERSION {
+            return Err(VersionError::Schema {
+                found: self.schema_version,
+                supported: SCHEMA_VERSION,
+            });
+        }
+        if self.ra_ap_version != RA_AP_VERSION {
+            return Err(VersionError::RaAp {
+                found: self.ra_ap_version.clone(),
+                supported: RA_AP_VERSION.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for BundleHeader {
+    fn default() -> Self {
+        BundleHeader::current()
+    }
+}
+
+/// A bundle was written for a different schema or `ra_ap_*` version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionError {
+    Unversioned,
+    Schema { found: u32, supported: u32 },
+    RaAp { found: String, supported: String },
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionError::Unversioned => write!(
+                f,
+                "bundle has no header, it was written before bundles were versioned"
+            ),
+            VersionError::Schema { found, supported } => write!(
+                f,
+                "bundle has schema version {}, but only version {} is supported",
+                found, supported
+            ),
+            VersionError::RaAp { found, supported } => write!(
+                f,
+                "bundle was extracted with ra_ap {}, but this build uses ra_ap {}",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VersionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_header_versions() {
+        assert_eq!(BundleHeader::new("0.1.0", None, 0).check(), Ok(()));
+        let header = BundleHeader {
+            schema_version: SCHEMA_VERSION + 1,
+            ..BundleHeader::current()
+        };
+        assert_eq!(
+            header.check(),
+            Err(VersionError::Schema {
+                found: SCHEMA_VERSION + 1,
+                supported: SCHEMA_VERSION
+            })
+        );
+        let header = BundleHeader {
+            ra_ap_version: "0.0.71".to_string(),
+            ..BundleHeader::current()
+        };
+        assert!(matches!(header.check(), Err(VersionError::RaAp { .. })));
+    }
+}
@@ -1,4 +1,23 @@
+mod bundle;
 mod change_json;
+mod compression;
 mod crate_graph_json;
+mod delta;
+mod error;
+mod header;
+mod merge;
+mod out_dir;
+mod proc_macro_json;
+mod subtree_json;
+mod sysroot;
 
+pub use crate::bundle::{BundleError, BundleFormat};
 pub use crate::change_json::ChangeJson;
+pub use crate::compression::{decompress, Compression};
+pub use crate::crate_graph_json::CrateIdError;
+pub use crate::error::ChangeError;
+pub use crate::header::{BundleHeader, VersionError, RA_AP_VERSION, SCHEMA_VERSION};
+pub use crate::merge::{MergeError, MergeLink};
+pub use crate::proc_macro_json::{ExpanderRegistry, ReplayExpander};
+pub use crate::subtree_json::expansion_key;
+pub use crate::sysroot::ComposeError;
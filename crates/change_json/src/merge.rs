@@ -0,0 +1,452 @@
+use crate::{
+    change_json::{FilesJson, SourceRootJson},
+    crate_graph_json::{CrateGraphJson, DepJson},
+    ChangeJson,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+/// Adds a dependency from the crates named `from.1` in bundle `from.0` to the
+/// crate named `to.1` in bundle `to.0`, replacing any dependency of the same
+/// name, e.g. on a version of the library pulled from crates.io.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeLink {
+    pub from: (usize, String),
+    pub to: (usize, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// A bundle still refers to a separate sysroot bundle, compose them first.
+    SysrootRef(usize),
+    /// A link refers to a bundle which is not being merged.
+    UnknownBundle(usize),
+    /// A link refers to a crate which is not part of its bundle.
+    UnknownCrate { bundle: usize, name: String },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::SysrootRef(bundle) => write!(
+                f,
+                "bundle {} refers to a sysroot bundle, compose them first",
+                bundle
+            ),
+            MergeError::UnknownBundle(bundle) => write!(f, "there is no bundle {}", bundle),
+            MergeError::UnknownCrate { bundle, name } => {
+                write!(f, "bundle {} has no crate `{}`", bundle, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+impl ChangeJson {
+    /// Combines several bundles into one, so that they can be analyzed side by
+    /// side.
+    ///
+    /// Files and crates of later bundles get ids past the ones of earlier
+    /// bundles. Library roots with the same paths and contents as a root of
+    /// an earlier bundle are only kept once, together with their crates.
+    pub fn merge(bundles: &[ChangeJson], links: &[MergeLink]) -> Result<ChangeJson, MergeError> {
+        let mut merged = ChangeJson {
+            header: bundles
+                .first()
+                .map(|bundle| bundle.header.clone())
+                .unwrap_or_default(),
+            crate_graph: Some(CrateGraphJson::default()),
+            local_roots: Some(SourceRootJson::default()),
+            library_roots: Some(SourceRootJson::default()),
+            ..ChangeJson::default()
+        };
+        // maps the display names of each bundle's crates to their merged ids
+        let mut crate_names: Vec<HashMap<String, Vec<u32>>> = Vec::new();
+
+        for (idx, bundle) in bundles.iter().enumerate() {
+            if bundle.sysroot.is_some() {
+                return Err(MergeError::SysrootRef(idx));
+            }
+            let file_offset = merged.max_file_id().map_or(0, |id| id + 1);
+            let crate_offset = merged
+                .crate_graph
+                .iter()
+                .flat_map(|graph| graph.crates.iter().map(|(id, _)| id + 1))
+                .max()
+                .unwrap_or(0);
+            let mut bundle = bundle.clone();
+            bundle.map_ids(&|id| id + file_offset, &|id| id + crate_offset);
+
+            let file_ids = merged.dedup_library_roots(&mut bundle);
+            bundle.map_ids(&|id| *file_ids.get(&id).unwrap_or(&id), &|id| id);
+            let crate_ids = merged.dedup_crates(&mut bundle);
+
+            let graph = bundle.crate_graph.take().unwrap_or_default();
+            let mut names: HashMap<String, Vec<u32>> = HashMap::new();
+            for (id, data) in graph.crates.iter() {
+                if let Some(name) = data.display_name.clone() {
+                    names.entry(name).or_default().push(*id);
+                }
+            }
+            // links may as well refer to crates which were deduplicated
+            for existing in crate_ids.values() {
+                let name = merged
+                    .crate_graph
+                    .iter()
+                    .flat_map(|graph| graph.crates.iter())
+                    .find(|(id, _)| id == existing)
+                    .and_then(|(_, data)| data.display_name.clone());
+                if let Some(name) = name {
+                    names.entry(name).or_default().push(*existing);
+                }
+            }
+            crate_names.push(names);
+
+            let merged_graph = merged.crate_graph.get_or_insert_with(Default::default);
+            merged_graph.crates.extend(graph.crates);
+            // deduplicated crates may have dependencies of the same name as
+            // the crates they were merged into, which keep theirs
+            let mut dep_names = merged_graph
+                .deps
+                .iter()
+                .map(|dep| (dep.from, dep.name.clone()))
+                .collect::<HashSet<_>>();
+            for mut dep in graph.deps {
+                dep.to = *crate_ids.get(&dep.to).unwrap_or(&dep.to);
+                if dep_names.insert((dep.from, dep.name.clone())) {
+                    merged_graph.deps.push(dep);
+                }
+            }
+            if let Some(roots) = bundle.local_roots {
+                merged
+                    .local_roots
+                    .get_or_insert_with(Default::default)
+                    .roots
+                    .extend(roots.roots);
+            }
+            if let Some(roots) = bundle.library_roots {
+                merged
+                    .library_roots
+                    .get_or_insert_with(Default::default)
+                    .roots
+                    .extend(roots.roots);
+            }
+            merged
+                .files_changed
+                .files
+                .extend(bundle.files_changed.files);
+            merged
+                .proc_macro_expansions
+                .extend(bundle.proc_macro_expansions);
+        }
+        merged.proc_macro_expansions.sort_by_key(|(key, _)| *key);
+        merged.proc_macro_expansions.dedup_by_key(|(key, _)| *key);
+
+        for link in links {
+            merged.link(&crate_names, link)?;
+        }
+        merged.compact_ids();
+        Ok(merged)
+    }
+
+    /// Removes the library roots of `bundle` which are already part of `self`
+    /// with the same paths and contents. Returns how the ids of the removed
+    /// files map to the ids of `self`.
+    fn dedup_library_roots(&self, bundle: &mut ChangeJson) -> HashMap<u32, u32> {
+        let texts = |json: &ChangeJson| {
+            json.files_changed
+                .files
+                .iter()
+                .map(|(id, text)| (*id, text.clone()))
+                .collect::<HashMap<_, _>>()
+        };
+        let contents = |root: &[(u32, Option<String>)], texts: &HashMap<u32, Option<String>>| {
+            let mut contents = root
+                .iter()
+                .map(|(id, path)| (path.clone(), texts.get(id).cloned().flatten()))
+                .collect::<Vec<_>>();
+            contents.sort();
+            contents
+        };
+        let merged_texts = texts(self);
+        let bundle_texts = texts(bundle);
+        let existing = self
+            .library_roots
+            .iter()
+            .flat_map(|roots| roots.roots.iter())
+            .map(|root| (contents(root, &merged_texts), root))
+            .collect::<Vec<_>>();
+
+        let mut file_ids = HashMap::new();
+        if let Some(library_roots) = bundle.library_roots.as_mut() {
+            library_roots.roots.retain(|root| {
+                let root_contents = contents(root, &bundle_texts);
+                let duplicate = existing
+                    .iter()
+                    .find(|(existing, _)| *existing == root_contents);
+                match duplicate {
+                    Some((_, existing)) => {
+                        for (id, path) in root.iter() {
+                            if let Some((existing_id, _)) =
+                                existing.iter().find(|(_, existing)| existing == path)
+                            {
+                                file_ids.insert(*id, *existing_id);
+                            }
+                        }
+                        false
+                    }
+                    None => true,
+                }
+            });
+        }
+        bundle
+            .files_changed
+            .files
+            .retain(|(id, _)| !file_ids.contains_key(id));
+        file_ids
+    }
+
+    /// Removes the crates of `bundle` which are equal to a crate of `self`,
+    /// their dependencies are moved to the crates of `self`. Returns how the
+    /// ids of the removed crates map to the ids of `self`.
+    fn dedup_crates(&self, bundle: &mut ChangeJson) -> HashMap<u32, u32> {
+        let mut crate_ids = HashMap::new();
+        let existing = match self.crate_graph.as_ref() {
+            Some(graph) => &graph.crates,
+            None => return crate_ids,
+        };
+        if let Some(graph) = bundle.crate_graph.as_mut() {
+            graph.crates.retain(|(id, data)| {
+                match existing.iter().find(|(_, existing)| existing == data) {
+                    Some((existing_id, _)) => {
+                        crate_ids.insert(*id, *existing_id);
+                        false
+                    }
+                    None => true,
+                }
+            });
+            for dep in graph.deps.iter_mut() {
+                if let Some(existing_id) = crate_ids.get(&dep.from) {
+                    dep.from = *existing_id;
+                }
+            }
+        }
+        crate_ids
+    }
+
+    fn link(
+        &mut self,
+        crate_names: &[HashMap<String, Vec<u32>>],
+        link: &MergeLink,
+    ) -> Result<(), MergeError> {
+        let from = lookup(crate_names, &link.from)?.to_vec();
+        let candidates = lookup(crate_names, &link.to)?;
+        let graph = self.crate_graph.get_or_insert_with(Default::default);
+        // a package's binaries, tests and examples share the name of its
+        // library, which is the one other crates depend on
+        let to = candidates
+            .iter()
+            .find(|id| graph.deps.iter().any(|dep| dep.to == **id))
+            .unwrap_or(&candidates[0]);
+        let name = link.to.1.replace('-', "_");
+        graph
+            .deps
+            .retain(|dep| !(from.contains(&dep.from) && dep.name == name));
+        graph
+            .deps
+            .extend(from.iter().filter(|id| *id != to).map(|from| DepJson {
+                from: *from,
+                name: name.clone(),
+                to: *to,
+            }));
+        Ok(())
+    }
+}
+
+fn lookup<'a>(
+    crate_names: &'a [HashMap<String, Vec<u32>>],
+    (bundle, name): &(usize, String),
+) -> Result<&'a [u32], MergeError> {
+    crate_names
+        .get(*bundle)
+        .ok_or(MergeError::UnknownBundle(*bundle))?
+        .get(name)
+        .filter(|ids| !ids.is_empty())
+        .map(|ids| ids.as_slice())
+        .ok_or_else(|| MergeError::UnknownCrate {
+            bundle: *bundle,
+            name: name.clone(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crate_graph_json::CrateDataJson;
+
+    fn krate(root_file_id: u32, name: &str) -> CrateDataJson {
+        CrateDataJson {
+            root_file_id,
+            edition: "2018".to_string(),
+            display_name: Some(name.to_string()),
+            ..CrateDataJson::default()
+        }
+    }
+
+    fn dep(from: u32, name: &str, to: u32) -> DepJson {
+        DepJson {
+            from,
+            name: name.to_string(),
+            to,
+        }
+    }
+
+    fn bundle(local: (&str, &str), library: (&str, &str)) -> ChangeJson {
+        ChangeJson {
+            crate_graph: Some(CrateGraphJson {
+                crates: vec![(0, krate(0, local.0)), (1, krate(1, library.0))],
+                deps: vec![dep(0, library.0, 1)],
+            }),
+            local_roots: Some(SourceRootJson {
+                roots: vec![vec![(0, Some(format!("/{}/lib.rs", local.0)))]],
+            }),
+            library_roots: Some(SourceRootJson {
+                roots: vec![vec![(1, Some(format!("/{}/lib.rs", library.0)))]],
+            }),
+            files_changed: FilesJson {
+                files: vec![
+                    (0, Some(local.1.to_string())),
+                    (1, Some(library.1.to_string())),
+                ],
+            },
+            ..ChangeJson::default()
+        }
+    }
+
+    #[test]
+    fn merge_dedups_identical_library_roots() {
+        let library = bundle(("library", "pub fn f() {}"), ("core", "// core"));
+        let consumer = bundle(("consumer", "fn main() {}"), ("core", "// core"));
+        let merged = ChangeJson::merge(&[library, consumer], &[]).unwrap();
+        assert_eq!(merged.library_roots.as_ref().unwrap().roots.len(), 1);
+        assert_eq!(merged.local_roots.as_ref().unwrap().roots.len(), 2);
+        assert_eq!(merged.files_changed.files.len(), 3);
+        let graph = merged.crate_graph.as_ref().unwrap();
+        assert_eq!(graph.crates.len(), 3);
+        assert_eq!(graph.deps.len(), 2);
+        assert!(graph.deps.iter().all(|dep| dep.to == 1));
+        assert_eq!(merged.try_to_change().unwrap().1, vec![]);
+    }
+
+    #[test]
+    fn merge_keeps_library_roots_with_other_contents() {
+        let library = bundle(("library", "pub fn f() {}"), ("core", "// core"));
+        let consumer = bundle(("consumer", "fn main() {}"), ("core", "// other core"));
+        let merged = ChangeJson::merge(&[library, consumer], &[]).unwrap();
+        assert_eq!(merged.library_roots.as_ref().unwrap().roots.len(), 2);
+        assert_eq!(merged.crate_graph.as_ref().unwrap().crates.len(), 4);
+        assert_eq!(merged.try_to_change().unwrap().1, vec![]);
+    }
+
+    #[test]
+    fn merge_keeps_deps_of_deduplicated_crates() {
+        let root = |id, name: &str| vec![(id, Some(format!("/{}/lib.rs", name)))];
+        let text = |id, text: &str| (id, Some(text.to_string()));
+        let app = ChangeJson {
+            crate_graph: Some(CrateGraphJson {
+                crates: vec![(0, krate(0, "app")), (1, krate(1, "util"))],
+                deps: vec![dep(0, "util", 1)],
+            }),
+            local_roots: Some(SourceRootJson {
+                roots: vec![root(0, "app")],
+            }),
+            library_roots: Some(SourceRootJson {
+                roots: vec![root(1, "util")],
+            }),
+            files_changed: FilesJson {
+                files: vec![text(0, "fn main() {}"), text(1, "// util")],
+            },
+            ..ChangeJson::default()
+        };
+        // the same `util`, which depends on `log` in this bundle
+        let util = ChangeJson {
+            crate_graph: Some(CrateGraphJson {
+                crates: vec![(0, krate(0, "util")), (1, krate(1, "log"))],
+                deps: vec![dep(0, "log", 1)],
+            }),
+            library_roots: Some(SourceRootJson {
+                roots: vec![root(0, "util"), root(1, "log")],
+            }),
+            files_changed: FilesJson {
+                files: vec![text(0, "// util"), text(1, "// log")],
+            },
+            ..ChangeJson::default()
+        };
+        let merged = ChangeJson::merge(&[app, util], &[]).unwrap();
+        let graph = merged.crate_graph.as_ref().unwrap();
+        assert_eq!(graph.crates.len(), 3);
+        let id = |name: &str| {
+            graph
+                .crates
+                .iter()
+                .find(|(_, data)| data.display_name.as_deref() == Some(name))
+                .unwrap()
+                .0
+        };
+        assert_eq!(
+            graph.deps,
+            vec![
+                dep(id("app"), "util", id("util")),
+                dep(id("util"), "log", id("log"))
+            ]
+        );
+        assert_eq!(merged.try_to_change().unwrap().1, vec![]);
+    }
+
+    #[test]
+    fn merge_links_crates_by_name() {
+        let library = bundle(("library", "pub fn f() {}"), ("core", "// core"));
+        let consumer = bundle(("consumer", "fn main() {}"), ("library", "// crates.io"));
+        let link = MergeLink {
+            from: (1, "consumer".to_string()),
+            to: (0, "library".to_string()),
+        };
+        let merged = ChangeJson::merge(&[library, consumer], &[link]).unwrap();
+        let graph = merged.crate_graph.as_ref().unwrap();
+        let id = |name: &str, root_file_id| {
+            graph
+                .crates
+                .iter()
+                .find(|(_, data)| {
+                    data.display_name.as_deref() == Some(name) && data.root_file_id == root_file_id
+                })
+                .unwrap()
+                .0
+        };
+        let consumer = id("consumer", 2);
+        let deps = graph
+            .deps
+            .iter()
+            .filter(|dep| dep.from == consumer)
+            .collect::<Vec<_>>();
+        assert_eq!(deps.len(), 1);
+        assert_eq!(deps[0].to, id("library", 0));
+
+        let link = MergeLink {
+            from: (1, "consumer".to_string()),
+            to: (0, "missing".to_string()),
+        };
+        let library = bundle(("library", ""), ("core", ""));
+        let consumer = bundle(("consumer", ""), ("core", ""));
+        assert_eq!(
+            ChangeJson::merge(&[library, consumer], &[link]).unwrap_err(),
+            MergeError::UnknownCrate {
+                bundle: 0,
+                name: "missing".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use crate::ChangeJson;
+
+impl ChangeJson {
+    /// Moves the files generated by build scripts from their `OUT_DIR` in the
+    /// target directory to the virtual path `/out_dir/<n>`, and points the
+    /// `OUT_DIR` of the crates there, so that bundles don't depend on the
+    /// location of the target directory.
+    pub fn virtualize_out_dirs(&mut self) {
+        // the out dirs found so far, with their virtual replacement
+        let mut out_dirs: Vec<(String, String)> = Vec::new();
+        let crates = self
+            .crate_graph
+            .iter_mut()
+            .flat_map(|graph| graph.crates.iter_mut());
+        for (_, data) in crates {
+            let out_dir = data
+                .env
+                .env
+                .iter_mut()
+                .find(|(key, _)| key == "OUT_DIR")
+                .map(|(_, value)| value);
+            if let Some(out_dir) = out_dir {
+                let virtual_dir = match out_dirs.iter().find(|(real, _)| real == out_dir) {
+                    Some((_, virtual_dir)) => virtual_dir.clone(),
+                    None => {
+                        let virtual_dir = format!("/out_dir/{}", out_dirs.len());
+                        out_dirs.push((out_dir.clone(), virtual_dir.clone()));
+                        virtual_dir
+                    }
+                };
+                *out_dir = virtual_dir;
+            }
+        }
+
+        let paths = self
+            .local_roots
+            .iter_mut()
+            .chain(self.library_roots.iter_mut())
+            .flat_map(|roots| roots.roots.iter_mut().flatten())
+            .filter_map(|(_, path)| path.as_mut());
+        for path in paths {
+            let replacement = out_dirs.iter().find_map(|(real, virtual_dir)| {
+                let rest = path.strip_prefix(real.as_str())?;
+                if rest.starts_with('/') {
+                    Some(format!("{}{}", virtual_dir, rest))
+                } else {
+                    None
+                }
+            });
+            if let Some(replacement) = replacement {
+                *path = replacement;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        change_json::SourceRootJson,
+        crate_graph_json::{CrateDataJson, CrateGraphJson, EnvJson},
+    };
+
+    #[test]
+    fn out_dirs_are_virtualized() {
+        let out_dir = "/p/target/debug/build/p-1234/out";
+        let krate = |root_file_id| CrateDataJson {
+            root_file_id,
+            env: EnvJson {
+                env: vec![
+                    ("CARGO_PKG_NAME".to_string(), "p".to_string()),
+                    ("OUT_DIR".to_string(), out_dir.to_string()),
+                ],
+            },
+            ..CrateDataJson::default()
+        };
+        let mut json = ChangeJson {
+            crate_graph: Some(CrateGraphJson {
+                crates: vec![(0, krate(0)), (1, krate(1))],
+                deps: Vec::new(),
+            }),
+            local_roots: Some(SourceRootJson {
+                roots: vec![vec![
+                    (0, Some("/p/src/lib.rs".to_string())),
+                    (1, Some("/p/src/main.rs".to_string())),
+                    (2, Some(format!("{}/generated.rs", out_dir))),
+                    (3, Some(format!("{}-other/lib.rs", out_dir))),
+                ]],
+            }),
+            ..ChangeJson::default()
+        };
+        json.virtualize_out_dirs();
+        let graph = json.crate_graph.as_ref().unwrap();
+        for (_, data) in graph.crates.iter() {
+            assert_eq!(data.env.env[1].1, "/out_dir/0");
+        }
+        let paths = json.local_roots.unwrap().roots[0]
+            .iter()
+            .map(|(_, path)| path.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths[2], "/out_dir/0/generated.rs");
+        assert_eq!(paths[3], format!("{}-other/lib.rs", out_dir));
+    }
+}
@@ -0,0 +1,203 @@
+use base_db::{Env, ProcMacro, ProcMacroExpander, ProcMacroExpansionError, ProcMacroKind};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tt::{SmolStr, Subtree};
+
+use crate::subtree_json::expansion_key;
+
+/// Recorded proc macro outputs, keyed by [`expansion_key`].
+pub(crate) type Expansions = HashMap<u64, Subtree>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct ProcMacroJson {
+    name: String,
+    kind: ProcMacroKindJson,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum ProcMacroKindJson {
+    CustomDerive,
+    FuncLike,
+    Attr,
+}
+
+impl ProcMacroJson {
+    pub(crate) fn from(proc_macro: &ProcMacro) -> Self {
+        let name = proc_macro.name.to_string();
+        let kind = match proc_macro.kind {
+            ProcMacroKind::CustomDerive => ProcMacroKindJson::CustomDerive,
+            ProcMacroKind::FuncLike => ProcMacroKindJson::FuncLike,
+            ProcMacroKind::Attr => ProcMacroKindJson::Attr,
+        };
+        ProcMacroJson { name, kind }
+    }
+
+    /// Uses the expander `registry` has for the proc macro of crate `krate`,
+    /// or else replays `expansions`.
+    pub(crate) fn to_proc_macro(
+        &self,
+        krate: Option<&str>,
+        registry: &ExpanderRegistry,
+        expansions: &Arc<Expansions>,
+    ) -> ProcMacro {
+        let name = SmolStr::from(&self.name);
+        let kind = match self.kind {
+            ProcMacroKindJson::CustomDerive => ProcMacroKind::CustomDerive,
+            ProcMacroKindJson::FuncLike => ProcMacroKind::FuncLike,
+            ProcMacroKindJson::Attr => ProcMacroKind::Attr,
+        };
+        let expander = krate
+            .and_then(|krate| registry.get(krate, &self.name))
+            .unwrap_or_else(|| Arc::new(ReplayExpander::new(name.clone(), expansions.clone())));
+        ProcMacro {
+            name,
+            kind,
+            expander,
+        }
+    }
+}
+
+/// Expanders provided by the host, e.g. pure Rust shims for common derives.
+///
+/// Deserialized proc macros use the expander registered for their crate and
+/// name instead of replaying recorded expansions.
+#[derive(Debug, Default, Clone)]
+pub struct ExpanderRegistry {
+    expanders: HashMap<(String, String), Arc<dyn ProcMacroExpander>>,
+}
+
+impl ExpanderRegistry {
+    pub fn new() -> Self {
+        ExpanderRegistry::default()
+    }
+
+    /// Registers `expander` for the proc macro `name` exported by the crate
+    /// `krate`, replacing any expander registered before.
+    pub fn register(&mut self, krate: &str, name: &str, expander: Arc<dyn ProcMacroExpander>) {
+        self.expanders
+            .insert((normalize(krate), name.to_string()), expander);
+    }
+
+    pub(crate) fn get(&self, krate: &str, name: &str) -> Option<Arc<dyn ProcMacroExpander>> {
+        self.expanders
+            .get(&(normalize(krate), name.to_string()))
+            .cloned()
+    }
+}
+
+/// Crate names are registered the same way whether they are spelled with
+/// dashes or underscores.
+fn normalize(krate: &str) -> String {
+    krate.replace('-', "_")
+}
+
+/// Expander attached to every deserialized proc macro which has no expander
+/// registered.
+///
+/// Proc macro dylibs can't be loaded from WASM, so the bundle carries the
+/// name and kind of each macro together with the expansions the extractor
+/// recorded natively. Inputs that were never recorded fail to expand.
+#[derive(Debug)]
+pub struct ReplayExpander {
+    name: SmolStr,
+    expansions: Arc<Expansions>,
+}
+
+impl ReplayExpander {
+    pub fn new(name: SmolStr, expansions: Arc<HashMap<u64, Subtree>>) -> Self {
+        ReplayExpander { name, expansions }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl ProcMacroExpander for ReplayExpander {
+    fn expand(
+        &self,
+        subtree: &Subtree,
+        attrs: Option<&Subtree>,
+        env: &Env,
+    ) -> Result<Subtree, ProcMacroExpansionError> {
+        let key = expansion_key(&self.name, subtree, attrs, env);
+        self.expansions.get(&key).cloned().ok_or_else(|| {
+            ProcMacroExpansionError::System(format!(
+                "no recorded expansion for proc macro `{}`",
+                self.name
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tt::{Ident, Leaf, TokenId, TokenTree};
+
+    #[test]
+    fn replay_recorded_expansion() {
+        let ident = |text: &str| Subtree {
+            delimiter: None,
+            token_trees: vec![TokenTree::Leaf(Leaf::Ident(Ident {
+                text: text.into(),
+                id: TokenId(0),
+            }))],
+        };
+        let env = Env::default();
+        let mut expansions = Expansions::default();
+        expansions.insert(
+            expansion_key("Serialize", &ident("Foo"), None, &env),
+            ident("impl"),
+        );
+        let proc_macro = ProcMacroJson {
+            name: "Serialize".to_string(),
+            kind: ProcMacroKindJson::CustomDerive,
+        }
+        .to_proc_macro(
+            Some("serde_derive"),
+            &Default::default(),
+            &Arc::new(expansions),
+        );
+
+        let expanded = proc_macro.expander.expand(&ident("Foo"), None, &env);
+        assert_eq!(expanded.unwrap(), ident("impl"));
+        let missing = proc_macro.expander.expand(&ident("Bar"), None, &env);
+        assert!(matches!(missing, Err(ProcMacroExpansionError::System(_))));
+    }
+
+    #[test]
+    fn registered_expander_is_preferred() {
+        let proc_macro = ProcMacroJson {
+            name: "Serialize".to_string(),
+            kind: ProcMacroKindJson::CustomDerive,
+        };
+        let mut registry = ExpanderRegistry::new();
+        registry.register("serde-derive", "Serialize", Arc::new(NoopExpander));
+        let expansions = Arc::new(Expansions::default());
+        let subtree = Subtree::default();
+        let env = Env::default();
+
+        let registered = proc_macro.to_proc_macro(Some("serde_derive"), &registry, &expansions);
+        assert_eq!(
+            registered.expander.expand(&subtree, None, &env).unwrap(),
+            subtree
+        );
+        let other = proc_macro.to_proc_macro(Some("other"), &registry, &expansions);
+        assert!(other.expander.expand(&subtree, None, &env).is_err());
+    }
+
+    #[derive(Debug)]
+    struct NoopExpander;
+
+    impl ProcMacroExpander for NoopExpander {
+        fn expand(
+            &self,
+            _subtree: &Subtree,
+            _attrs: Option<&Subtree>,
+            _env: &Env,
+        ) -> Result<Subtree, ProcMacroExpansionError> {
+            Ok(Subtree::default())
+        }
+    }
+}
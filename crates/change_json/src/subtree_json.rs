@@ -0,0 +1,188 @@
+use base_db::Env;
+use serde::{Deserialize, Serialize};
+use tt::{
+    Delimiter, DelimiterKind, Ident, Leaf, Literal, Punct, SmolStr, Spacing, Subtree, TokenId,
+    TokenTree,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct SubtreeJson {
+    delimiter: Option<(u32, DelimiterKindJson)>,
+    token_trees: Vec<TokenTreeJson>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum DelimiterKindJson {
+    Parenthesis,
+    Brace,
+    Bracket,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+enum TokenTreeJson {
+    Literal { text: String, id: u32 },
+    Punct { char: char, joint: bool, id: u32 },
+    Ident { text: String, id: u32 },
+    Subtree(SubtreeJson),
+}
+
+impl SubtreeJson {
+    pub(crate) fn from(subtree: &Subtree) -> Self {
+        let delimiter = subtree.delimiter.map(|delimiter| {
+            let kind = match delimiter.kind {
+                DelimiterKind::Parenthesis => DelimiterKindJson::Parenthesis,
+                DelimiterKind::Brace => DelimiterKindJson::Brace,
+                DelimiterKind::Bracket => DelimiterKindJson::Bracket,
+            };
+            (delimiter.id.0, kind)
+        });
+        let token_trees = subtree
+            .token_trees
+            .iter()
+            .map(|token_tree| match token_tree {
+                TokenTree::Leaf(Leaf::Literal(literal)) => TokenTreeJson::Literal {
+                    text: literal.text.to_string(),
+                    id: literal.id.0,
+                },
+                TokenTree::Leaf(Leaf::Punct(punct)) => TokenTreeJson::Punct {
+                    char: punct.char,
+                    joint: punct.spacing == Spacing::Joint,
+                    id: punct.id.0,
+                },
+                TokenTree::Leaf(Leaf::Ident(ident)) => TokenTreeJson::Ident {
+                    text: ident.text.to_string(),
+                    id: ident.id.0,
+                },
+                TokenTree::Subtree(subtree) => TokenTreeJson::Subtree(SubtreeJson::from(subtree)),
+            })
+            .collect();
+        SubtreeJson {
+            delimiter,
+            token_trees,
+        }
+    }
+
+    pub(crate) fn to_subtree(&self) -> Subtree {
+        let delimiter = self.delimiter.map(|(id, kind)| Delimiter {
+            id: TokenId(id),
+            kind: match kind {
+                DelimiterKindJson::Parenthesis => DelimiterKind::Parenthesis,
+                DelimiterKindJson::Brace => DelimiterKind::Brace,
+                DelimiterKindJson::Bracket => DelimiterKind::Bracket,
+            },
+        });
+        let token_trees = self
+            .token_trees
+            .iter()
+            .map(|token_tree| match token_tree {
+                TokenTreeJson::Literal { text, id } => TokenTree::Leaf(Leaf::Literal(Literal {
+                    text: SmolStr::from(text),
+                    id: TokenId(*id),
+                })),
+                TokenTreeJson::Punct { char, joint, id } => TokenTree::Leaf(Leaf::Punct(Punct {
+                    char: *char,
+                    spacing: if *joint {
+                        Spacing::Joint
+                    } else {
+                        Spacing::Alone
+                    },
+                    id: TokenId(*id),
+                })),
+                TokenTreeJson::Ident { text, id } => TokenTree::Leaf(Leaf::Ident(Ident {
+                    text: SmolStr::from(text),
+                    id: TokenId(*id),
+                })),
+                TokenTreeJson::Subtree(subtree) => TokenTree::Subtree(subtree.to_subtree()),
+            })
+            .collect();
+        Subtree {
+            delimiter,
+            token_trees,
+        }
+    }
+}
+
+/// Identifies the expansion of the proc macro `name` for the given input.
+///
+/// The key is stable across runs and platforms, so that expansions recorded
+/// by the extractor can be looked up again from WASM.
+pub fn expansion_key(name: &str, subtree: &Subtree, attrs: Option<&Subtree>, env: &Env) -> u64 {
+    // `OUT_DIR` is virtualized after the expansions have been recorded
+    let mut env = env
+        .iter()
+        .filter(|(key, _)| *key != "OUT_DIR")
+        .collect::<Vec<_>>();
+    env.sort_unstable();
+    let input = (
+        name,
+        SubtreeJson::from(subtree),
+        attrs.map(SubtreeJson::from),
+        env,
+    );
+    let bytes = serde_json::to_vec(&input).expect("serialization of subtrees must work");
+    // FNV-1a
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subtree() -> Subtree {
+        let ident = |text: &str, id| {
+            TokenTree::Leaf(Leaf::Ident(Ident {
+                text: text.into(),
+                id: TokenId(id),
+            }))
+        };
+        Subtree {
+            delimiter: None,
+            token_trees: vec![
+                ident("struct", 0),
+                ident("Foo", 1),
+                TokenTree::Subtree(Subtree {
+                    delimiter: Some(Delimiter {
+                        id: TokenId(2),
+                        kind: DelimiterKind::Brace,
+                    }),
+                    token_trees: vec![
+                        ident("x", 3),
+                        TokenTree::Leaf(Leaf::Punct(Punct {
+                            char: ':',
+                            spacing: Spacing::Alone,
+                            id: TokenId(4),
+                        })),
+                        TokenTree::Leaf(Leaf::Literal(Literal {
+                            text: "1u8".into(),
+                            id: TokenId(5),
+                        })),
+                    ],
+                }),
+            ],
+        }
+    }
+
+    #[test]
+    fn subtree_round_trip() {
+        let subtree = subtree();
+        assert_eq!(SubtreeJson::from(&subtree).to_subtree(), subtree);
+    }
+
+    #[test]
+    fn expansion_key_depends_on_all_inputs() {
+        let subtree = subtree();
+        let env = Env::default();
+        let key = expansion_key("Serialize", &subtree, None, &env);
+        assert_eq!(key, expansion_key("Serialize", &subtree, None, &env));
+        assert_ne!(key, expansion_key("Deserialize", &subtree, None, &env));
+        assert_ne!(
+            key,
+            expansion_key("Serialize", &subtree, Some(&subtree), &env)
+        );
+        let mut other_env = Env::default();
+        other_env.set("CARGO_PKG_NAME", "foo".to_string());
+        assert_ne!(key, expansion_key("Serialize", &subtree, None, &other_env));
+    }
+}
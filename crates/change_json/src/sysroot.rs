@@ -0,0 +1,449 @@
+use crate::{
+    change_json::{FilesJson, SourceRootJson},
+    crate_graph_json::{CrateDataJson, CrateGraphJson, DepJson},
+    ChangeJson,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt};
+
+/// Sources of the stub sysroot crates, with the crates each depends on.
+const STUB_SYSROOT: &[(&str, &str, &[&str])] = &[
+    ("core", include_str!("../stub_sysroot/core.rs"), &[]),
+    ("alloc", include_str!("../stub_sysroot/alloc.rs"), &["core"]),
+    (
+        "std",
+        include_str!("../stub_sysroot/std.rs"),
+        &["core", "alloc"],
+    ),
+];
+
+/// Marks a project bundle whose sysroot crates live in a separate bundle.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct SysrootRefJson {
+    /// `rustc` version of the toolchain the sysroot was extracted from.
+    rustc_version: Option<String>,
+    deps: Vec<SysrootDepJson>,
+}
+
+/// Dependency of a project crate on a sysroot crate, which is referred to by
+/// its display name since its id is only known after composing the bundles.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SysrootDepJson {
+    from: u32,
+    name: String,
+    to: String,
+}
+
+impl SysrootRefJson {
+    pub(crate) fn map_crate_ids(&mut self, crate_id: &dyn Fn(u32) -> u32) {
+        self.deps
+            .iter_mut()
+            .for_each(|dep| dep.from = crate_id(dep.from));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComposeError {
+    /// The project bundle doesn't refer to a sysroot bundle.
+    NotAProjectBundle,
+    /// The sysroot bundle refers to another sysroot bundle itself.
+    NotASysrootBundle,
+    /// The bundles were extracted with different toolchains.
+    ToolchainMismatch {
+        sysroot: Option<String>,
+        project: Option<String>,
+    },
+    /// The project depends on a crate the sysroot bundle doesn't contain.
+    UnknownSysrootCrate(String),
+    /// No library root lies in the sysroot, so there is nothing to split off.
+    NoSysrootRoots,
+}
+
+impl fmt::Display for ComposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComposeError::NotAProjectBundle => {
+                write!(f, "bundle does not refer to a sysroot bundle")
+            }
+            ComposeError::NotASysrootBundle => {
+                write!(f, "sysroot bundle refers to another sysroot bundle")
+            }
+            ComposeError::ToolchainMismatch { sysroot, project } => write!(
+                f,
+                "sysroot bundle was extracted with {}, but the project with {}",
+                sysroot.as_deref().unwrap_or("an unknown toolchain"),
+                project.as_deref().unwrap_or("an unknown toolchain")
+            ),
+            ComposeError::UnknownSysrootCrate(name) => {
+                write!(f, "sysroot bundle has no crate `{}`", name)
+            }
+            ComposeError::NoSysrootRoots => write!(f, "no library root lies in the sysroot"),
+        }
+    }
+}
+
+impl std::error::Error for ComposeError {}
+
+impl ChangeJson {
+    /// Splits the bundle into a sysroot bundle, containing every library root
+    /// whose paths all satisfy `is_sysroot_path` together with their crates,
+    /// and a project bundle referring to it.
+    ///
+    /// Both bundles have their ids renumbered to `0..n`, use
+    /// [`ChangeJson::compose`] to load them together. Fails if no library
+    /// root satisfies `is_sysroot_path`.
+    pub fn split_sysroot(
+        &self,
+        is_sysroot_path: &dyn Fn(&str) -> bool,
+    ) -> Result<(ChangeJson, ChangeJson), ComposeError> {
+        let library_roots = self.library_roots.clone().unwrap_or_default().roots;
+        let (sysroot_roots, library_roots): (Vec<_>, Vec<_>) =
+            library_roots.into_iter().partition(|root| {
+                !root.is_empty()
+                    && root
+                        .iter()
+                        .all(|(_, path)| path.as_deref().map_or(false, is_sysroot_path))
+            });
+        if sysroot_roots.is_empty() {
+            return Err(ComposeError::NoSysrootRoots);
+        }
+        let sysroot_files = sysroot_roots
+            .iter()
+            .flatten()
+            .map(|(id, _)| *id)
+            .collect::<HashSet<_>>();
+
+        let graph = self.crate_graph.clone().unwrap_or_default();
+        let (sysroot_crates, crates): (Vec<_>, Vec<_>) = graph
+            .crates
+            .into_iter()
+            .partition(|(_, data)| sysroot_files.contains(&data.root_file_id));
+        let is_sysroot_crate = |id: u32| sysroot_crates.iter().any(|(krate, _)| *krate == id);
+        let mut sysroot_deps = Vec::new();
+        let mut deps = Vec::new();
+        let mut external_deps = Vec::new();
+        for dep in graph.deps {
+            match (is_sysroot_crate(dep.from), is_sysroot_crate(dep.to)) {
+                (true, true) => sysroot_deps.push(dep),
+                (false, false) => deps.push(dep),
+                (false, true) => {
+                    let to = sysroot_crates
+                        .iter()
+                        .find(|(krate, _)| *krate == dep.to)
+                        .and_then(|(_, data)| data.display_name.clone())
+                        .unwrap_or_else(|| dep.name.clone());
+                    external_deps.push(SysrootDepJson {
+                        from: dep.from,
+                        name: dep.name,
+                        to,
+                    })
+                }
+                // sysroot crates never depend on project crates
+                (true, false) => (),
+            }
+        }
+        let (sysroot_files, files): (Vec<_>, Vec<_>) = self
+            .files_changed
+            .files
+            .iter()
+            .cloned()
+            .partition(|(id, _)| sysroot_files.contains(id));
+
+        let mut sysroot = ChangeJson {
+            header: self.header.clone(),
+            sysroot: None,
+            crate_graph: Some(CrateGraphJson {
+                crates: sysroot_crates,
+                deps: sysroot_deps,
+            }),
+            proc_macro_expansions: Vec::new(),
+            local_roots: Some(SourceRootJson::default()),
+            library_roots: Some(SourceRootJson {
+                roots: sysroot_roots,
+            }),
+            files_changed: FilesJson {
+                files: sysroot_files,
+            },
+        };
+        let mut project = ChangeJson {
+            header: self.header.clone(),
+            sysroot: Some(SysrootRefJson {
+                rustc_version: self.header.rustc_version.clone(),
+                deps: external_deps,
+            }),
+            crate_graph: Some(CrateGraphJson { crates, deps }),
+            proc_macro_expansions: self.proc_macro_expansions.clone(),
+            local_roots: self.local_roots.clone(),
+            library_roots: Some(SourceRootJson {
+                roots: library_roots,
+            }),
+            files_changed: FilesJson { files },
+        };
+        sysroot.compact_ids();
+        project.compact_ids();
+        Ok((sysroot, project))
+    }
+
+    /// Combines a sysroot bundle and a project bundle created by
+    /// [`ChangeJson::split_sysroot`], moving the project's ids past the ones
+    /// of the sysroot.
+    pub fn compose(sysroot: &ChangeJson, project: &ChangeJson) -> Result<ChangeJson, ComposeError> {
+        if sysroot.sysroot.is_some() {
+            return Err(ComposeError::NotASysrootBundle);
+        }
+        let sysroot_ref = project
+            .sysroot
+            .as_ref()
+            .ok_or(ComposeError::NotAProjectBundle)?;
+        if sysroot_ref.rustc_version != sysroot.header.rustc_version {
+            return Err(ComposeError::ToolchainMismatch {
+                sysroot: sysroot.header.rustc_version.clone(),
+                project: sysroot_ref.rustc_version.clone(),
+            });
+        }
+
+        let mut graph = sysroot.crate_graph.clone().unwrap_or_default();
+        let file_offset = sysroot.max_file_id().map_or(0, |id| id + 1);
+        let crate_offset = graph.crates.iter().map(|(id, _)| id + 1).max().unwrap_or(0);
+        let mut project = project.clone();
+        project.map_ids(&|id| id + file_offset, &|id| id + crate_offset);
+
+        for dep in project.sysroot.take().into_iter().flat_map(|it| it.deps) {
+            let to = graph
+                .crates
+                .iter()
+                .find(|(_, data)| data.display_name.as_deref() == Some(dep.to.as_str()))
+                .map(|(id, _)| *id)
+                .ok_or_else(|| ComposeError::UnknownSysrootCrate(dep.to.clone()))?;
+            graph.deps.push(DepJson {
+                from: dep.from,
+                name: dep.name,
+                to,
+            });
+        }
+        let project_graph = project.crate_graph.take().unwrap_or_default();
+        graph.crates.extend(project_graph.crates);
+        graph.deps.extend(project_graph.deps);
+
+        let mut library_roots = sysroot.library_roots.clone().unwrap_or_default();
+        library_roots
+            .roots
+            .extend(project.library_roots.take().unwrap_or_default().roots);
+        let mut files_changed = sysroot.files_changed.clone();
+        files_changed.files.extend(project.files_changed.files);
+
+        Ok(ChangeJson {
+            header: project.header,
+            sysroot: None,
+            crate_graph: Some(graph),
+            proc_macro_expansions: project.proc_macro_expansions,
+            local_roots: project.local_roots,
+            library_roots: Some(library_roots),
+            files_changed,
+        })
+    }
+
+    /// Adds minimal `core`, `alloc` and `std` crates which every crate
+    /// depends on, so that the prelude and common std paths resolve in a
+    /// bundle extracted without a sysroot.
+    ///
+    /// Returns `false` and leaves the bundle alone if it already has a `core`
+    /// crate or refers to a sysroot bundle.
+    pub fn add_stub_sysroot(&mut self) -> bool {
+        if self.sysroot.is_some() {
+            return false;
+        }
+        let mut file_id = self.max_file_id().map_or(0, |id| id + 1);
+        let graph = self.crate_graph.get_or_insert_with(Default::default);
+        if graph
+            .crates
+            .iter()
+            .any(|(_, data)| data.display_name.as_deref() == Some("core"))
+        {
+            return false;
+        }
+        let project_crates = graph.crates.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let mut crate_id = project_crates.iter().map(|id| id + 1).max().unwrap_or(0);
+
+        let mut root = Vec::new();
+        let mut stub_crates: Vec<(&str, u32)> = Vec::new();
+        for (name, text, deps) in STUB_SYSROOT.iter() {
+            root.push((file_id, Some(format!("/stub_sysroot/{}/lib.rs", name))));
+            self.files_changed
+                .files
+                .push((file_id, Some(text.to_string())));
+            graph.crates.push((
+                crate_id,
+                CrateDataJson {
+                    root_file_id: file_id,
+                    edition: "2018".to_string(),
+                    display_name: Some(name.to_string()),
+                    ..CrateDataJson::default()
+                },
+            ));
+            for (dep, to) in stub_crates.iter().filter(|(dep, _)| deps.contains(dep)) {
+                graph.deps.push(DepJson {
+                    from: crate_id,
+                    name: dep.to_string(),
+                    to: *to,
+                });
+            }
+            stub_crates.push((*name, crate_id));
+            file_id += 1;
+            crate_id += 1;
+        }
+        for from in project_crates {
+            for (name, to) in stub_crates.iter() {
+                let exists = graph
+                    .deps
+                    .iter()
+                    .any(|dep| dep.from == from && dep.name == *name);
+                if !exists {
+                    graph.deps.push(DepJson {
+                        from,
+                        name: name.to_string(),
+                        to: *to,
+                    });
+                }
+            }
+        }
+        self.library_roots
+            .get_or_insert_with(Default::default)
+            .roots
+            .push(root);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChangeError;
+    use base_db::{
+        Change, CrateGraph, CrateName, Edition, Env, FileId, FileSet, SourceRoot, VfsPath,
+    };
+    use cfg::CfgOptions;
+    use std::sync::Arc;
+
+    fn add_crate(graph: &mut CrateGraph, file_id: u32, name: &str) -> base_db::CrateId {
+        graph.add_crate_root(
+            FileId(file_id),
+            Edition::Edition2018,
+            Some(base_db::CrateDisplayName::from_canonical_name(
+                name.to_string(),
+            )),
+            CfgOptions::default(),
+            CfgOptions::default(),
+            Env::default(),
+            Vec::new(),
+        )
+    }
+
+    fn source_root(files: &[(u32, &str)], library: bool) -> SourceRoot {
+        let mut file_set = FileSet::default();
+        for (id, path) in files {
+            file_set.insert(FileId(*id), VfsPath::new_virtual_path(path.to_string()));
+        }
+        if library {
+            SourceRoot::new_library(file_set)
+        } else {
+            SourceRoot::new_local(file_set)
+        }
+    }
+
+    #[test]
+    fn split_and_compose_sysroot() {
+        let mut graph = CrateGraph::default();
+        let project = add_crate(&mut graph, 2, "project");
+        let core = add_crate(&mut graph, 5, "core");
+        let dep = add_crate(&mut graph, 7, "dep");
+        let core_name = CrateName::new("core").unwrap();
+        graph.add_dep(project, core_name.clone(), core).unwrap();
+        graph
+            .add_dep(project, CrateName::new("dep").unwrap(), dep)
+            .unwrap();
+        graph.add_dep(dep, core_name, core).unwrap();
+        let mut change = Change::new();
+        change.set_roots(vec![
+            source_root(&[(2, "/project/src/lib.rs")], false),
+            source_root(&[(5, "/sysroot/core/src/lib.rs")], true),
+            source_root(&[(7, "/registry/dep/src/lib.rs")], true),
+        ]);
+        for (id, text) in [(2, "pub fn f() {}"), (5, "pub mod option {}"), (7, "")].iter() {
+            change.change_file(FileId(*id), Some(Arc::new(text.to_string())));
+        }
+        change.set_crate_graph(graph);
+        let json = ChangeJson::from(&change);
+
+        assert_eq!(
+            json.split_sysroot(&|path| path.starts_with("sysroot/"))
+                .unwrap_err(),
+            ComposeError::NoSysrootRoots
+        );
+        let (sysroot, project) = json
+            .split_sysroot(&|path| path.starts_with("/sysroot/"))
+            .unwrap();
+        assert_eq!(sysroot.crate_graph.as_ref().unwrap().crates.len(), 1);
+        assert_eq!(project.crate_graph.as_ref().unwrap().crates.len(), 2);
+        assert_eq!(project.sysroot.as_ref().unwrap().deps.len(), 2);
+        assert_eq!(
+            ChangeJson::compose(&project, &project).unwrap_err(),
+            ComposeError::NotASysrootBundle
+        );
+        let (_, warnings) = project.try_to_change().unwrap();
+        assert_eq!(warnings, vec![ChangeError::UncomposedSysroot]);
+
+        let composed = ChangeJson::compose(&sysroot, &project).unwrap();
+        let graph = composed.crate_graph.as_ref().unwrap();
+        assert_eq!(graph.crates.len(), 3);
+        assert_eq!(graph.deps.len(), 3);
+        assert_eq!(composed.files_changed.files.len(), 3);
+        let (change, warnings) = composed.try_to_change().unwrap();
+        assert_eq!(warnings, vec![]);
+        let graph = change.crate_graph.unwrap();
+        let project = graph
+            .iter()
+            .find(|id| graph[*id].display_name.as_deref() == Some("project"))
+            .unwrap();
+        let roots = change.roots.unwrap();
+        let core = graph[project]
+            .dependencies
+            .iter()
+            .find(|dep| dep.name.to_string() == "core")
+            .unwrap();
+        let core_root = graph[core.crate_id].root_file_id;
+        let path = roots
+            .iter()
+            .find_map(|root| root.path_for_file(&core_root))
+            .unwrap();
+        assert_eq!(path.to_string(), "/sysroot/core/src/lib.rs");
+    }
+
+    #[test]
+    fn add_stub_sysroot() {
+        let mut graph = CrateGraph::default();
+        add_crate(&mut graph, 0, "project");
+        let mut change = Change::new();
+        change.set_roots(vec![source_root(&[(0, "/project/src/lib.rs")], false)]);
+        change.change_file(FileId(0), Some(Arc::new("pub fn f() {}".to_string())));
+        change.set_crate_graph(graph);
+        let mut json = ChangeJson::from(&change);
+
+        assert!(json.add_stub_sysroot());
+        assert!(!json.add_stub_sysroot());
+        let (change, warnings) = json.try_to_change().unwrap();
+        assert_eq!(warnings, vec![]);
+        let graph = change.crate_graph.unwrap();
+        assert_eq!(graph.iter().count(), 4);
+        let project = graph
+            .iter()
+            .find(|id| graph[*id].display_name.as_deref() == Some("project"))
+            .unwrap();
+        let deps = graph[project]
+            .dependencies
+            .iter()
+            .map(|dep| dep.name.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(deps, vec!["core", "alloc", "std"]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Minimal stand-in for `alloc`, used when a bundle has no real sysroot.
+#![no_std]
+
+pub mod boxed {
+    #[lang = "owned_box"]
+    pub struct Box<T: ?Sized>(*mut T);
+
+    impl<T> Box<T> {
+        pub fn new(x: T) -> Box<T> {
+            loop {}
+        }
+    }
+}
+
+pub mod vec {
+    pub struct Vec<T> {
+        ptr: *mut T,
+        len: usize,
+    }
+
+    impl<T> Vec<T> {
+        pub fn new() -> Vec<T> {
+            loop {}
+        }
+        pub fn push(&mut self, value: T) {}
+        pub fn pop(&mut self) -> Option<T> {
+            None
+        }
+        pub fn len(&self) -> usize {
+            self.len
+        }
+        pub fn is_empty(&self) -> bool {
+            self.len == 0
+        }
+    }
+}
+
+pub mod string {
+    pub struct String {
+        vec: crate::vec::Vec<u8>,
+    }
+
+    impl String {
+        pub fn new() -> String {
+            loop {}
+        }
+        pub fn push_str(&mut self, string: &str) {}
+        pub fn len(&self) -> usize {
+            self.vec.len()
+        }
+        pub fn is_empty(&self) -> bool {
+            self.vec.is_empty()
+        }
+    }
+
+    pub trait ToString {
+        fn to_string(&self) -> String;
+    }
+}
+
+pub mod borrow {
+    pub trait ToOwned {
+        type Owned;
+        fn to_owned(&self) -> Self::Owned;
+    }
+}
+
+#[macro_export]
+macro_rules! vec {
+    ($($x:expr),* $(,)?) => {
+        $crate::vec::Vec::new()
+    };
+    ($elem:expr; $n:expr) => {
+        $crate::vec::Vec::new()
+    };
+}
+
+#[macro_export]
+macro_rules! format {
+    ($($arg:tt)*) => {
+        $crate::string::String::new()
+    };
+}
@@ -0,0 +1,269 @@
+//! Minimal stand-in for `core`, used when a bundle has no real sysroot.
+//!
+//! Only declares enough items for name resolution and basic type inference.
+#![no_core]
+#![feature(no_core, lang_items, rustc_attrs, decl_macro)]
+
+pub mod marker {
+    #[lang = "sized"]
+    pub trait Sized {}
+    #[lang = "copy"]
+    pub trait Copy: Clone {}
+    pub unsafe auto trait Send {}
+    pub unsafe auto trait Sync {}
+    #[lang = "unsize"]
+    pub trait Unsize<T: ?Sized> {}
+    #[lang = "phantom_data"]
+    pub struct PhantomData<T: ?Sized>;
+
+    #[rustc_builtin_macro]
+    pub macro Copy($item:item) {}
+}
+
+pub mod clone {
+    pub trait Clone: Sized {
+        fn clone(&self) -> Self;
+    }
+
+    #[rustc_builtin_macro]
+    pub macro Clone($item:item) {}
+}
+
+pub mod default {
+    pub trait Default: Sized {
+        fn default() -> Self;
+    }
+
+    #[rustc_builtin_macro]
+    pub macro Default($item:item) {}
+}
+
+pub mod cmp {
+    #[lang = "eq"]
+    pub trait PartialEq<Rhs: ?Sized = Self> {
+        fn eq(&self, other: &Rhs) -> bool;
+    }
+    pub trait Eq: PartialEq<Self> {}
+    #[lang = "partial_ord"]
+    pub trait PartialOrd<Rhs: ?Sized = Self>: PartialEq<Rhs> {}
+    pub trait Ord: Eq + PartialOrd<Self> {}
+
+    #[rustc_builtin_macro]
+    pub macro PartialEq($item:item) {}
+    #[rustc_builtin_macro]
+    pub macro Eq($item:item) {}
+    #[rustc_builtin_macro]
+    pub macro PartialOrd($item:item) {}
+    #[rustc_builtin_macro]
+    pub macro Ord($item:item) {}
+}
+
+pub mod hash {
+    pub trait Hash {}
+
+    #[rustc_builtin_macro]
+    pub macro Hash($item:item) {}
+}
+
+pub mod fmt {
+    pub struct Formatter;
+    pub struct Error;
+    pub type Result = crate::result::Result<(), Error>;
+
+    pub trait Debug {
+        fn fmt(&self, f: &mut Formatter) -> Result;
+    }
+    pub trait Display {
+        fn fmt(&self, f: &mut Formatter) -> Result;
+    }
+
+    #[rustc_builtin_macro]
+    pub macro Debug($item:item) {}
+}
+
+pub mod convert {
+    pub trait From<T>: Sized {
+        fn from(value: T) -> Self;
+    }
+    pub trait Into<T>: Sized {
+        fn into(self) -> T;
+    }
+    pub trait AsRef<T: ?Sized> {
+        fn as_ref(&self) -> &T;
+    }
+    pub trait AsMut<T: ?Sized> {
+        fn as_mut(&mut self) -> &mut T;
+    }
+}
+
+pub mod ops {
+    #[lang = "drop"]
+    pub trait Drop {
+        fn drop(&mut self);
+    }
+    #[lang = "deref"]
+    pub trait Deref {
+        #[lang = "deref_target"]
+        type Target: ?Sized;
+        fn deref(&self) -> &Self::Target;
+    }
+    #[lang = "fn_once"]
+    pub trait FnOnce<Args> {
+        #[lang = "fn_once_output"]
+        type Output;
+    }
+    #[lang = "fn_mut"]
+    pub trait FnMut<Args>: FnOnce<Args> {}
+    #[lang = "fn"]
+    pub trait Fn<Args>: FnMut<Args> {}
+    #[lang = "add"]
+    pub trait Add<Rhs = Self> {
+        type Output;
+        fn add(self, rhs: Rhs) -> Self::Output;
+    }
+    #[lang = "index"]
+    pub trait Index<Idx: ?Sized> {
+        type Output: ?Sized;
+        fn index(&self, index: Idx) -> &Self::Output;
+    }
+    #[lang = "Range"]
+    pub struct Range<Idx> {
+        pub start: Idx,
+        pub end: Idx,
+    }
+}
+
+pub mod option {
+    pub enum Option<T> {
+        #[lang = "None"]
+        None,
+        #[lang = "Some"]
+        Some(T),
+    }
+    pub use self::Option::{None, Some};
+}
+
+pub mod result {
+    pub enum Result<T, E> {
+        #[lang = "Ok"]
+        Ok(T),
+        #[lang = "Err"]
+        Err(E),
+    }
+    pub use self::Result::{Err, Ok};
+}
+
+pub mod iter {
+    pub trait Iterator {
+        type Item;
+        #[lang = "next"]
+        fn next(&mut self) -> crate::option::Option<Self::Item>;
+    }
+    pub trait IntoIterator {
+        type Item;
+        type IntoIter: Iterator<Item = Self::Item>;
+        #[lang = "into_iter"]
+        fn into_iter(self) -> Self::IntoIter;
+    }
+    pub trait Extend<A> {}
+    pub trait FromIterator<A>: Sized {}
+    pub trait DoubleEndedIterator: Iterator {}
+    pub trait ExactSizeIterator: Iterator {}
+}
+
+pub mod mem {
+    pub fn drop<T>(_x: T) {}
+}
+
+pub mod prelude {
+    pub mod v1 {
+        pub use crate::clone::Clone;
+        pub use crate::cmp::{Eq, Ord, PartialEq, PartialOrd};
+        pub use crate::convert::{AsMut, AsRef, From, Into};
+        pub use crate::default::Default;
+        pub use crate::fmt::Debug;
+        pub use crate::hash::Hash;
+        pub use crate::iter::{DoubleEndedIterator, ExactSizeIterator};
+        pub use crate::iter::{Extend, IntoIterator, Iterator};
+        pub use crate::marker::{Copy, Send, Sized, Sync};
+        pub use crate::mem::drop;
+        pub use crate::ops::{Drop, Fn, FnMut, FnOnce};
+        pub use crate::option::Option::{self, None, Some};
+        pub use crate::result::Result::{self, Err, Ok};
+        pub use crate::{assert, assert_eq, panic, unreachable};
+    }
+
+    pub mod rust_2015 {
+        pub use super::v1::*;
+    }
+    pub mod rust_2018 {
+        pub use super::v1::*;
+    }
+    pub mod rust_2021 {
+        pub use super::v1::*;
+    }
+}
+
+#[prelude_import]
+use prelude::v1::*;
+
+#[macro_export]
+#[rustc_builtin_macro]
+macro_rules! format_args {
+    ($fmt:expr) => {{}};
+    ($fmt:expr, $($args:tt)*) => {{}};
+}
+
+#[macro_export]
+#[rustc_builtin_macro]
+macro_rules! concat {
+    ($($e:expr),* $(,)?) => {{}};
+}
+
+#[macro_export]
+#[rustc_builtin_macro]
+macro_rules! stringify {
+    ($($t:tt)*) => {{}};
+}
+
+#[macro_export]
+#[rustc_builtin_macro]
+macro_rules! env {
+    ($name:expr $(,)?) => {{}};
+}
+
+#[macro_export]
+#[rustc_builtin_macro]
+macro_rules! include {
+    ($file:expr $(,)?) => {{}};
+}
+
+#[macro_export]
+macro_rules! panic {
+    ($($arg:tt)*) => {
+        loop {}
+    };
+}
+
+#[macro_export]
+macro_rules! unreachable {
+    ($($arg:tt)*) => {
+        $crate::panic!($($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! assert {
+    ($cond:expr $(, $($arg:tt)+)?) => {
+        if !$cond {
+            $crate::panic!()
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! assert_eq {
+    ($left:expr, $right:expr $(, $($arg:tt)+)?) => {
+        $crate::assert!($left == $right)
+    };
+}
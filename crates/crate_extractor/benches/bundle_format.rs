@@ -0,0 +1,36 @@
+//! Compares how long it takes to read the bundle of this repository's own
+//! workspace in each [`BundleFormat`].
+
+use std::path::Path;
+
+use change_json::{BundleFormat, ChangeJson};
+use crate_extractor::load_change::{load_workspace_at, LoadCargoConfig};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use project_model::CargoConfig;
+
+fn load_bundle(c: &mut Criterion) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap();
+    let cargo_config = CargoConfig::default();
+    let load_cargo_config = LoadCargoConfig::default();
+    let (change, _vfs, _proc_macro, _primed) =
+        load_workspace_at(path, &cargo_config, &load_cargo_config, &|_| {}).unwrap();
+    let json = ChangeJson::from(&change);
+
+    let mut group = c.benchmark_group("load_bundle");
+    group.sample_size(10);
+    for format in [BundleFormat::Json, BundleFormat::Binary].iter() {
+        let bytes = json.to_bytes(*format).unwrap();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(format.to_string(), |b| {
+            b.iter(|| ChangeJson::from_bytes(&bytes).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, load_bundle);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+//! Turns loaded workspaces into bundles, the same way for every subcommand.
+
+use change_json::{BundleHeader, ChangeJson};
+use ide::Change;
+
+/// Post-processing applied to every bundle of a workspace, and to the
+/// deltas of its later changes.
+#[derive(Debug, Clone, Default)]
+pub struct BundleOptions {
+    pub header: BundleHeader,
+    /// Add stub `core`, `alloc` and `std` crates if the workspace has no
+    /// sysroot, see [`ChangeJson::add_stub_sysroot`].
+    pub stub_sysroot: bool,
+}
+
+impl BundleOptions {
+    /// Converts the change of a loaded workspace into a bundle.
+    pub fn to_bundle(&self, change: &Change) -> ChangeJson {
+        self.finish(ChangeJson::from(change))
+    }
+
+    fn finish(&self, json: ChangeJson) -> ChangeJson {
+        let mut json = json.with_header(self.header.clone());
+        if self.stub_sysroot {
+            json.add_stub_sysroot();
+        }
+        // bundles must not depend on the location of the target directory
+        json.virtualize_out_dirs();
+        json
+    }
+}
+
+/// A bundle kept up to date with the changes of its workspace.
+///
+/// Changes are applied to the bundle as it was converted and then processed
+/// again, so that the deltas are consistent with the bundle, e.g. keep the
+/// `OUT_DIR`s virtualized.
+pub struct LiveBundle {
+    options: BundleOptions,
+    /// The bundle before post-processing.
+    converted: ChangeJson,
+    bundle: ChangeJson,
+}
+
+impl LiveBundle {
+    pub fn new(change: &Change, options: BundleOptions) -> Self {
+        let converted = ChangeJson::from(change);
+        let bundle = options.finish(converted.clone());
+        LiveBundle {
+            options,
+            converted,
+            bundle,
+        }
+    }
+
+    pub fn bundle(&self) -> &ChangeJson {
+        &self.bundle
+    }
+
+    /// Applies a change of the workspace and returns the delta which turns
+    /// the previous bundle into the new one, see [`ChangeJson::diff`].
+    pub fn update(&mut self, change: &Change) -> ChangeJson {
+        self.converted.apply(&ChangeJson::from(change));
+        let bundle = self.options.finish(self.converted.clone());
+        let delta = ChangeJson::diff(&self.bundle, &bundle);
+        self.bundle = bundle;
+        delta
+    }
+}
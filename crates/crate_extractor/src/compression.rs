@@ -0,0 +1,39 @@
+use std::io::{self, Write};
+
+use change_json::Compression;
+use flate2::write::GzEncoder;
+
+/// Compresses a serialized bundle, `change_json::decompress` reverses this.
+pub fn compress(bytes: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(bytes.to_vec()),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        Compression::Zstd => zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn compressed_bundles_decompress() {
+        let text = br#"{"header":{"schema_version":1}}"#;
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd].iter() {
+            let compressed = compress(text, *compression).unwrap();
+            assert_eq!(Compression::detect(&compressed), *compression);
+            let mut plain = Vec::new();
+            change_json::decompress(&compressed[..])
+                .unwrap()
+                .read_to_end(&mut plain)
+                .unwrap();
+            assert_eq!(&plain[..], &text[..]);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{fmt, io, path::Path, str::FromStr};
+
+use change_json::BundleError;
+use crate_extractor::load_change::LoadError;
+
+/// Failures of the command line tool, told apart by their exit code.
+#[derive(Debug)]
+pub(crate) struct CliError {
+    kind: ErrorKind,
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    Other,
+    /// Invalid arguments, or no subcommand given.
+    Usage,
+    ManifestNotFound,
+    /// `cargo metadata` failed, or the `rust-project.json` is invalid.
+    Metadata,
+    SysrootMissing,
+    Io,
+    /// A bundle could not be serialized, read or combined.
+    Bundle,
+    /// Analyzing the extracted bundle panicked.
+    Analysis,
+}
+
+/// How errors are printed to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorFormat {
+    Human,
+    /// One JSON object per error, for wrappers which need to tell failures
+    /// apart.
+    Json,
+}
+
+impl CliError {
+    pub(crate) fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        CliError {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn io(path: &Path, err: io::Error) -> Self {
+        CliError::new(ErrorKind::Io, format!("{}: {}", path.display(), err))
+    }
+
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self.kind {
+            ErrorKind::Other => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::ManifestNotFound => 3,
+            ErrorKind::Metadata => 4,
+            ErrorKind::SysrootMissing => 5,
+            ErrorKind::Io => 6,
+            ErrorKind::Bundle => 7,
+            ErrorKind::Analysis => 8,
+        }
+    }
+
+    fn kind_name(&self) -> &'static str {
+        match self.kind {
+            ErrorKind::Other => "other",
+            ErrorKind::Usage => "usage",
+            ErrorKind::ManifestNotFound => "manifest_not_found",
+            ErrorKind::Metadata => "metadata",
+            ErrorKind::SysrootMissing => "sysroot_missing",
+            ErrorKind::Io => "io",
+            ErrorKind::Bundle => "bundle",
+            ErrorKind::Analysis => "analysis",
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": self.kind_name(),
+            "message": self.message,
+            "exit_code": self.exit_code(),
+        })
+    }
+
+    pub(crate) fn report(&self, format: ErrorFormat) {
+        match format {
+            ErrorFormat::Human => eprintln!("error: {}", self),
+            ErrorFormat::Json => eprintln!("{}", self.to_json()),
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<anyhow::Error> for CliError {
+    fn from(err: anyhow::Error) -> Self {
+        let kind = match err.downcast_ref::<LoadError>() {
+            Some(LoadError::ManifestNotFound(_)) => ErrorKind::ManifestNotFound,
+            Some(LoadError::Metadata) => ErrorKind::Metadata,
+            Some(LoadError::SysrootMissing) => ErrorKind::SysrootMissing,
+            Some(LoadError::BuildScripts) => ErrorKind::Other,
+            None if err.downcast_ref::<io::Error>().is_some() => ErrorKind::Io,
+            None => ErrorKind::Other,
+        };
+        CliError::new(kind, format!("{:#}", err))
+    }
+}
+
+impl From<clap::Error> for CliError {
+    fn from(err: clap::Error) -> Self {
+        let message = err.message.trim_start_matches("error: ");
+        CliError::new(ErrorKind::Usage, message)
+    }
+}
+
+impl From<BundleError> for CliError {
+    fn from(err: BundleError) -> Self {
+        let kind = match err {
+            BundleError::Io(_) => ErrorKind::Io,
+            _ => ErrorKind::Bundle,
+        };
+        CliError::new(kind, err.to_string())
+    }
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format `{}`", s)),
+        }
+    }
+}
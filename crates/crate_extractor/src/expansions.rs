@@ -0,0 +1,129 @@
+use std::sync::Mutex;
+
+use hir::db::DefDatabase;
+use ide::{AnalysisHost, AssistResolveStrategy, Change, DiagnosticsConfig};
+use ide_db::base_db::{FileId, SourceDatabase};
+
+/// Collects the outputs of the native proc macro expanders, so that they can
+/// be replayed from a bundle where no proc macro server is available.
+#[derive(Debug, Default)]
+pub struct ExpansionRecorder {
+    expansions: Mutex<Vec<(u64, tt::Subtree)>>,
+}
+
+impl ExpansionRecorder {
+    pub fn record(&self, key: u64, subtree: &tt::Subtree) {
+        self.expansions.lock().unwrap().push((key, subtree.clone()));
+    }
+
+    /// Returns every expansion recorded so far.
+    pub fn take(&self) -> Vec<(u64, tt::Subtree)> {
+        std::mem::take(&mut *self.expansions.lock().unwrap())
+    }
+}
+
+/// Expands the macro calls in the items of all crates of `change` and in
+/// the function bodies of its local crates, by computing the def maps of all
+/// crates and the diagnostics of all local files.
+///
+/// Macro calls in function bodies of library crates are not expanded, so
+/// they fail to replay if a host analyzes these bodies.
+pub fn expand_all(change: Change) {
+    let local_files = change
+        .roots
+        .iter()
+        .flatten()
+        .filter(|root| !root.is_library)
+        .flat_map(|root| root.iter())
+        .collect::<Vec<FileId>>();
+    let mut host = AnalysisHost::default();
+    host.apply_change(change);
+    let db = host.raw_database();
+    let graph = db.crate_graph();
+    for krate in graph.iter() {
+        db.crate_def_map(krate);
+    }
+    let analysis = host.analysis();
+    let config = DiagnosticsConfig::default();
+    for file_id in local_files {
+        if let Err(err) = analysis.diagnostics(&config, AssistResolveStrategy::None, file_id) {
+            log::warn!("expanding macros of {:?} was cancelled: {:?}", file_id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use change_json::{expansion_key, ChangeJson};
+    use ide_db::base_db::{Env, ProcMacroExpander, ProcMacroExpansionError};
+
+    use super::*;
+    use crate::test_utils::derive_change;
+
+    /// Records its expansions like the expanders of the proc macro server.
+    #[derive(Debug)]
+    struct RecordingExpander {
+        recorder: Arc<ExpansionRecorder>,
+        inputs: Mutex<Vec<(tt::Subtree, Env)>>,
+    }
+
+    impl ProcMacroExpander for RecordingExpander {
+        fn expand(
+            &self,
+            subtree: &tt::Subtree,
+            attrs: Option<&tt::Subtree>,
+            env: &Env,
+        ) -> Result<tt::Subtree, ProcMacroExpansionError> {
+            let expansion = tt::Subtree::default();
+            let key = expansion_key("Answer", subtree, attrs, env);
+            self.recorder.record(key, &expansion);
+            self.inputs
+                .lock()
+                .unwrap()
+                .push((subtree.clone(), env.clone()));
+            Ok(expansion)
+        }
+    }
+
+    #[test]
+    fn recorded_expansions_are_replayed() {
+        let recorder = Arc::new(ExpansionRecorder::default());
+        let expander = Arc::new(RecordingExpander {
+            recorder: recorder.clone(),
+            inputs: Mutex::default(),
+        });
+        let change = derive_change(
+            expander.clone(),
+            &[
+                ("util", "#[derive(answer::Answer)]\npub struct Util;", false),
+                ("app", "#[derive(answer::Answer)]\nstruct App;", true),
+            ],
+        );
+
+        let mut json = ChangeJson::from(&change);
+        expand_all(change);
+        json.set_expansions(recorder.take());
+
+        let inputs = expander.inputs.lock().unwrap();
+        let expanded = |name: &str| {
+            inputs
+                .iter()
+                .any(|(subtree, _)| format!("{:?}", subtree).contains(name))
+        };
+        // the derive in the library crate is expanded as well
+        assert!(expanded("Util"));
+        assert!(expanded("App"));
+        let graph = json.to_change().crate_graph.unwrap();
+        let replay = graph
+            .iter()
+            .flat_map(|krate| graph[krate].proc_macro.clone())
+            .next()
+            .unwrap();
+        for (subtree, env) in inputs.iter() {
+            let expanded = replay.expander.expand(subtree, None, env);
+            assert_eq!(expanded.unwrap(), tt::Subtree::default());
+        }
+    }
+}
@@ -0,0 +1,11 @@
+pub mod bundle;
+pub mod compression;
+pub mod expansions;
+pub mod load_change;
+pub mod prime_caches;
+mod reload;
+pub mod serve;
+pub mod stdio;
+#[cfg(test)]
+mod test_utils;
+pub mod watch;
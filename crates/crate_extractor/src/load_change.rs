@@ -1,40 +1,216 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use change_json::ChangeJson;
 use crossbeam_channel::{unbounded, Receiver};
 use ide::Change;
 use ide_db::base_db::CrateGraph;
 use proc_macro_api::ProcMacroServer;
-use project_model::{CargoConfig, ProjectManifest, ProjectWorkspace, WorkspaceBuildScripts};
+use project_model::{
+    CargoConfig, ProjectManifest, ProjectWorkspace, Sysroot, WorkspaceBuildScripts,
+};
 use vfs::{loader::Handle, AbsPath, AbsPathBuf};
 
-use crate::reload::{load_proc_macro, ProjectFolders, SourceRootConfig};
+use crate::{
+    expansions::ExpansionRecorder,
+    prime_caches::{prime_caches, CratePriming},
+    reload::{load_proc_macro, ProjectFolders, SourceRootConfig},
+    watch::Watcher,
+};
 
+#[derive(Default)]
 pub struct LoadCargoConfig {
     pub load_out_dirs_from_check: bool,
     pub with_proc_macro: bool,
+    /// Prime the caches of the local crates once loaded, as a check that the
+    /// extracted bundle analyzes, see [`prime_caches`].
     pub prefill_caches: bool,
+    /// Sources of the standard library to load instead of the ones found
+    /// through `RUST_SRC_PATH`, rustc or the `rust-project.json`. Loaded even
+    /// if `CargoConfig::no_sysroot` is set.
+    pub sysroot_src: Option<PathBuf>,
+    /// Receives every successful proc macro expansion.
+    pub expansion_recorder: Option<Arc<ExpansionRecorder>>,
+}
+
+/// Why loading a workspace failed. Attached to the errors of the `load_*`
+/// functions, so that callers can tell failures apart by downcasting.
+#[derive(Debug)]
+pub enum LoadError {
+    /// There is no `Cargo.toml` or `rust-project.json` at the given path.
+    ManifestNotFound(PathBuf),
+    /// `cargo metadata` failed, or the `rust-project.json` is invalid.
+    Metadata,
+    /// The sources of the standard library could not be found.
+    SysrootMissing,
+    BuildScripts,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::ManifestNotFound(path) => write!(
+                f,
+                "no Cargo.toml or rust-project.json found at {}",
+                path.display()
+            ),
+            LoadError::Metadata => write!(f, "failed to read the project metadata"),
+            LoadError::SysrootMissing => write!(
+                f,
+                "failed to find the sources of the standard library, is rust-src installed?"
+            ),
+            LoadError::BuildScripts => write!(f, "failed to run build scripts"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The phases of loading a workspace, in the order in which they are passed
+/// to the `progress` callback of the `load_*` functions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadProgress {
+    /// Running `cargo metadata` or reading the `rust-project.json`.
+    Metadata(String),
+    /// Running the build scripts and proc macros, with the crates being built.
+    BuildScripts(String),
+    /// Lowering the workspace into a crate graph, which loads the crate roots.
+    CrateGraph,
+    /// Loading the source roots of the crate graph.
+    Files { n_done: usize, n_total: usize },
+}
+
+impl fmt::Display for LoadProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadProgress::Metadata(message) => write!(f, "metadata: {}", message),
+            LoadProgress::BuildScripts(message) => write!(f, "build scripts: {}", message),
+            LoadProgress::CrateGraph => write!(f, "crate graph: lowering workspace"),
+            LoadProgress::Files { n_done, n_total } => {
+                write!(f, "files: {}/{} roots loaded", n_done, n_total)
+            }
+        }
+    }
 }
 
 pub fn load_workspace_at(
     root: &Path,
     cargo_config: &CargoConfig,
     load_config: &LoadCargoConfig,
-    progress: &dyn Fn(String),
-) -> Result<(Change, vfs::Vfs, Option<ProcMacroServer>)> {
-    let root = AbsPathBuf::assert(std::env::current_dir()?.join(root));
-    let root = ProjectManifest::discover_single(&root)?;
-    let workspace = ProjectWorkspace::load(root, cargo_config, progress)?;
-
+    progress: &dyn Fn(LoadProgress),
+) -> Result<(Change, vfs::Vfs, Option<ProcMacroServer>, Vec<CratePriming>)> {
+    let workspace = load_project_workspace(root, cargo_config, load_config, progress)?;
     load_workspace(workspace, cargo_config, load_config, progress)
 }
 
+/// Like [`load_workspace_at`], but keeps watching the member roots of the
+/// workspace for changes.
+pub fn watch_workspace_at(
+    root: &Path,
+    cargo_config: &CargoConfig,
+    load_config: &LoadCargoConfig,
+    progress: &dyn Fn(LoadProgress),
+) -> Result<(Change, Watcher, Option<ProcMacroServer>)> {
+    open_workspace_at(root, cargo_config, load_config, true, progress)
+}
+
+/// Loads the workspace at `root`, keeping the loaded files around so that
+/// changes can be picked up later, through watching if `watch` is set.
+pub(crate) fn open_workspace_at(
+    root: &Path,
+    cargo_config: &CargoConfig,
+    load_config: &LoadCargoConfig,
+    watch: bool,
+    progress: &dyn Fn(LoadProgress),
+) -> Result<(Change, Watcher, Option<ProcMacroServer>)> {
+    let workspace = load_project_workspace(root, cargo_config, load_config, progress)?;
+    load(workspace, cargo_config, load_config, watch, progress)
+}
+
+fn load_project_workspace(
+    root: &Path,
+    cargo_config: &CargoConfig,
+    load_config: &LoadCargoConfig,
+    progress: &dyn Fn(LoadProgress),
+) -> Result<ProjectWorkspace> {
+    // any `.json` file is taken as project description, whatever its name
+    let is_project_json = root.extension().map_or(false, |ext| ext == "json");
+    let not_found = || LoadError::ManifestNotFound(root.to_path_buf());
+    let abs_root = AbsPathBuf::assert(std::env::current_dir()?.join(root));
+    if !root.exists() {
+        return Err(not_found().into());
+    }
+    let manifest = if is_project_json {
+        ProjectManifest::ProjectJson(abs_root)
+    } else {
+        ProjectManifest::discover_single(&abs_root).with_context(not_found)?
+    };
+    progress(LoadProgress::Metadata(format!(
+        "reading {}",
+        root.display()
+    )));
+    // don't look for a sysroot which is replaced anyway
+    let cargo_config = &CargoConfig {
+        no_sysroot: cargo_config.no_sysroot || load_config.sysroot_src.is_some(),
+        ..cargo_config.clone()
+    };
+    let metadata_progress = |message| progress(LoadProgress::Metadata(message));
+    let mut workspace = ProjectWorkspace::load(manifest.clone(), cargo_config, &metadata_progress)
+        .map_err(|err| {
+            // only look for the cause once loading failed, discovery runs rustc
+            let sysroot_missing = match &manifest {
+                ProjectManifest::CargoToml(cargo_toml) => {
+                    !cargo_config.no_sysroot && Sysroot::discover(cargo_toml).is_err()
+                }
+                ProjectManifest::ProjectJson(_) => false,
+            };
+            err.context(if sysroot_missing {
+                LoadError::SysrootMissing
+            } else {
+                LoadError::Metadata
+            })
+        })?;
+    if let Some(sysroot_src) = load_config.sysroot_src.as_ref() {
+        let sysroot_src = AbsPathBuf::assert(std::env::current_dir()?.join(sysroot_src));
+        let loaded = Sysroot::load(&sysroot_src).context(LoadError::SysrootMissing)?;
+        match &mut workspace {
+            ProjectWorkspace::Cargo { sysroot, .. } => *sysroot = loaded,
+            ProjectWorkspace::Json { sysroot, .. } => *sysroot = Some(loaded),
+            ProjectWorkspace::DetachedFiles { sysroot, .. } => *sysroot = loaded,
+        }
+    }
+    Ok(workspace)
+}
+
+/// Loads `ws`, and primes the caches of its local crates as they end up in a
+/// bundle if [`LoadCargoConfig::prefill_caches`] is set.
 pub fn load_workspace(
+    ws: ProjectWorkspace,
+    cargo_config: &CargoConfig,
+    load_config: &LoadCargoConfig,
+    progress: &dyn Fn(LoadProgress),
+) -> Result<(Change, vfs::Vfs, Option<ProcMacroServer>, Vec<CratePriming>)> {
+    let (change, watcher, proc_macro_client) =
+        load(ws, cargo_config, load_config, false, progress)?;
+    let primed = if load_config.prefill_caches {
+        prime_caches(ChangeJson::from(&change).to_change())
+    } else {
+        Vec::new()
+    };
+    Ok((change, watcher.vfs, proc_macro_client, primed))
+}
+
+fn load(
     mut ws: ProjectWorkspace,
     cargo_config: &CargoConfig,
     load_config: &LoadCargoConfig,
-    progress: &dyn Fn(String),
-) -> Result<(Change, vfs::Vfs, Option<ProcMacroServer>)> {
+    watch: bool,
+    progress: &dyn Fn(LoadProgress),
+) -> Result<(Change, Watcher, Option<ProcMacroServer>)> {
     let (sender, receiver) = unbounded();
     let mut vfs = vfs::Vfs::default();
     let mut loader = {
@@ -43,21 +219,34 @@ pub fn load_workspace(
         Box::new(loader)
     };
 
+    // the server is this executable, started with the `proc-macro` subcommand
     let proc_macro_client = if load_config.with_proc_macro {
         let path = AbsPathBuf::assert(std::env::current_exe()?);
-        Some(ProcMacroServer::spawn(path, &["proc-macro"]).unwrap())
+        Some(ProcMacroServer::spawn(path, &["proc-macro"])?)
     } else {
         None
     };
 
     ws.set_build_scripts(if load_config.load_out_dirs_from_check {
-        ws.run_build_scripts(cargo_config, progress)?
+        progress(LoadProgress::BuildScripts(
+            "running cargo check".to_string(),
+        ));
+        let build_progress = |message| progress(LoadProgress::BuildScripts(message));
+        ws.run_build_scripts(cargo_config, &build_progress)
+            .context(LoadError::BuildScripts)?
     } else {
         WorkspaceBuildScripts::default()
     });
 
+    progress(LoadProgress::CrateGraph);
     let crate_graph = ws.to_crate_graph(
-        &mut |path: &AbsPath| load_proc_macro(proc_macro_client.as_ref(), path),
+        &mut |path: &AbsPath| {
+            load_proc_macro(
+                proc_macro_client.as_ref(),
+                load_config.expansion_recorder.as_ref(),
+                path,
+            )
+        },
         &mut |path: &AbsPath| {
             let contents = loader.load_sync(path);
             let path = vfs::VfsPath::from(path.to_path_buf());
@@ -69,26 +258,34 @@ pub fn load_workspace(
     let project_folders = ProjectFolders::new(&[ws], &[]);
     loader.set_config(vfs::loader::Config {
         load: project_folders.load,
-        watch: vec![],
+        watch: if watch { project_folders.watch } else { vec![] },
         version: 0,
     });
 
     log::debug!("crate graph: {:?}", crate_graph);
     let change = load_crate_graph(
         crate_graph,
-        project_folders.source_root_config,
+        &project_folders.source_root_config,
         &mut vfs,
         &receiver,
+        progress,
     );
 
-    Ok((change, vfs, proc_macro_client))
+    let watcher = Watcher {
+        vfs,
+        _loader: loader,
+        receiver,
+        source_root_config: project_folders.source_root_config,
+    };
+    Ok((change, watcher, proc_macro_client))
 }
 
 fn load_crate_graph(
     crate_graph: CrateGraph,
-    source_root_config: SourceRootConfig,
+    source_root_config: &SourceRootConfig,
     vfs: &mut vfs::Vfs,
     receiver: &Receiver<vfs::loader::Message>,
+    progress: &dyn Fn(LoadProgress),
 ) -> Change {
     let mut analysis_change = Change::new();
 
@@ -100,6 +297,7 @@ fn load_crate_graph(
                 n_total,
                 config_version: _,
             } => {
+                progress(LoadProgress::Files { n_done, n_total });
                 if n_done == n_total {
                     break;
                 }
@@ -1,27 +1,80 @@
-use std::{fs, path::Path};
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::Arc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use change_json::ChangeJson;
-use clap::{App, Arg};
-
-mod load_change;
-mod reload;
-
-use load_change::load_workspace_at;
+use change_json::{BundleFormat, BundleHeader, ChangeJson, Compression, MergeLink};
+use clap::{App, AppSettings, Arg, ArgMatches};
+use crate_extractor::{
+    bundle::{BundleOptions, LiveBundle},
+    compression::compress,
+    expansions::{expand_all, ExpansionRecorder},
+    load_change::{load_workspace_at, watch_workspace_at, LoadCargoConfig, LoadProgress},
+    serve::BundleServer,
+    stdio::Session,
+};
+use error::{CliError, ErrorFormat, ErrorKind};
 use project_model::CargoConfig;
 
-use crate::load_change::LoadCargoConfig;
+mod error;
 
 fn main() {
-    let matches = App::new("Trait Extractor")
+    let args = env::args_os().collect::<Vec<_>>();
+    let error_format = error_format(&args);
+    let result = match app().get_matches_from_safe(args) {
+        Ok(matches) => run(&matches),
+        // showing the help or version is no failure, clap exits with 0 there
+        Err(err)
+            if matches!(
+                err.kind,
+                clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed
+            ) =>
+        {
+            err.exit()
+        }
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = result {
+        err.report(error_format);
+        process::exit(err.exit_code());
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("Trait Extractor")
         .version("0.1")
         .author("Achim S. <achim@parity.io>")
         .about("Extract Crate Data to JSON for rust analyzer")
+        // usage errors are reported as JSON as well
+        .setting(AppSettings::ColorNever)
+        .arg(
+            Arg::with_name("error-format")
+                .help("How errors are printed, `human` or `json`")
+                .takes_value(true)
+                .long("error-format")
+                .possible_values(&["human", "json"])
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .help("Don't report the progress of loading a workspace")
+                .long("quiet")
+                .short("q")
+                .global(true),
+        )
         .subcommand(
             App::new("create")
                 .about("Create .json file for Rust Crate")
                 .arg(
                     Arg::with_name("path")
-                        .help("Path to Cargo.toml, defaults to ./Cargo.toml")
+                        .help("Path to Cargo.toml or rust-project.json, defaults to ./Cargo.toml")
                         .takes_value(true)
                         .short("i")
                         .long("input")
@@ -36,33 +89,604 @@ fn main() {
                         .long("output")
                         .multiple(false)
                         .required(false),
-                ),
+                )
+                .arg(format_arg())
+                .arg(compression_arg())
+                .arg(
+                    Arg::with_name("stable-file-ids")
+                        .help(
+                            "Number files by their paths instead of the order they were loaded in",
+                        )
+                        .long("stable-file-ids")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("sysroot-output")
+                        .help("Write the sysroot crates into a separate bundle at this path")
+                        .takes_value(true)
+                        .long("sysroot-output")
+                        .required(false),
+                )
+                .args(&cargo_args())
+                .args(&bundle_args())
+                .args(&analysis_args()),
+        )
+        .subcommand(
+            App::new("watch")
+                .about("Create a bundle and update it with a delta whenever files change")
+                .arg(
+                    Arg::with_name("path")
+                        .help("Path to Cargo.toml or rust-project.json, defaults to ./Cargo.toml")
+                        .takes_value(true)
+                        .short("i")
+                        .long("input")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output path for the bundle, defaults to ./change.json")
+                        .takes_value(true)
+                        .short("o")
+                        .long("output")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("delta-output")
+                        .help("Output path for the delta of each change, defaults to ./delta.json")
+                        .takes_value(true)
+                        .long("delta-output")
+                        .required(false),
+                )
+                .arg(format_arg())
+                .arg(compression_arg())
+                .args(&cargo_args())
+                .args(&bundle_args()),
         )
-        .get_matches();
+        .subcommand(
+            App::new("serve")
+                .about("Serve a bundle and the deltas of file changes over HTTP")
+                .arg(
+                    Arg::with_name("path")
+                        .help("Path to Cargo.toml or rust-project.json, defaults to ./Cargo.toml")
+                        .takes_value(true)
+                        .short("i")
+                        .long("input")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("addr")
+                        .help("Address to listen on, defaults to 127.0.0.1:8080")
+                        .takes_value(true)
+                        .long("addr")
+                        .required(false),
+                )
+                .args(&cargo_args())
+                .args(&bundle_args()),
+        )
+        .subcommand(
+            App::new("stdio")
+                .about("Answer line-delimited JSON requests from stdin on stdout")
+                .args(&cargo_args())
+                .args(&bundle_args()),
+        )
+        .subcommand(
+            App::new("proc-macro")
+                .about("Run the proc-macro server, spawned by extraction with --with-proc-macro")
+                .setting(AppSettings::Hidden),
+        )
+        .subcommand(
+            App::new("diff")
+                .about("Create a delta bundle which turns one bundle into another")
+                .arg(
+                    Arg::with_name("old")
+                        .help("Path to the bundle the delta is applied to")
+                        .takes_value(true)
+                        .long("old")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .help("Path to the bundle the delta turns the old bundle into")
+                        .takes_value(true)
+                        .long("new")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output path for the delta, defaults to ./delta.json")
+                        .takes_value(true)
+                        .short("o")
+                        .long("output")
+                        .required(false),
+                )
+                .arg(format_arg())
+                .arg(compression_arg()),
+        )
+        .subcommand(
+            App::new("merge")
+                .about("Merge several bundles into one analysis workspace")
+                .arg(
+                    Arg::with_name("input")
+                        .help("Paths to the bundles to merge")
+                        .takes_value(true)
+                        .short("i")
+                        .long("input")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output path for the merged bundle, defaults to ./change.json")
+                        .takes_value(true)
+                        .short("o")
+                        .long("output")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("link")
+                        .help("Add a dependency between bundles, e.g. 1:consumer=0:library")
+                        .takes_value(true)
+                        .long("link")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                )
+                .arg(format_arg())
+                .arg(compression_arg()),
+        )
+}
 
-    let cargo_config: CargoConfig = Default::default();
-    let load_cargo_config = LoadCargoConfig {
-        load_out_dirs_from_check: false,
-        with_proc_macro: false,
-        prefill_caches: false,
-    };
+/// The value of `--error-format`, which is needed to report that the
+/// arguments could not be parsed.
+fn error_format(args: &[OsString]) -> ErrorFormat {
+    let mut format = ErrorFormat::Human;
+    let mut args = args.iter().filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--error-format") {
+            Some("") => args.next(),
+            Some(value) => value.strip_prefix('='),
+            None => None,
+        };
+        if let Some(Ok(value)) = value.map(str::parse) {
+            format = value;
+        }
+    }
+    format
+}
+
+fn run(matches: &ArgMatches) -> Result<(), CliError> {
     match matches.subcommand_name() {
         Some("create") => {
             let matches = matches.subcommand_matches("create").unwrap();
             let path = matches.value_of("path").unwrap_or("./Cargo.toml");
             println!("Creating .json file, using: {}", path);
             let path = Path::new(path);
-            let output_path = matches.value_of("output").unwrap_or("./change.json");
-            let output_path = Path::new(output_path);
-            let res = load_workspace_at(path, &cargo_config, &load_cargo_config, &|_| {});
-            let (change, _, _) = res.unwrap_or_else(|err| panic!("Error loading workspace: {}", err));
-            let json = ChangeJson::from(&change);
-            let text = serde_json::to_string(&json).expect("serialization of change must work");
-            fs::write(output_path, text).expect("Unable to write file");
+            let (format, compression) = output_format(matches);
+            let output_path = bundle_path(matches, "output", "change", format);
+            let output_path = output_path.as_path();
+            let (cargo_config, load_cargo_config, bundle_options) =
+                extraction_options(matches, path);
+            let progress = progress_reporter(matches);
+            let (change, _, _, primed) =
+                load_workspace_at(path, &cargo_config, &load_cargo_config, &progress)?;
+            let mut json = bundle_options.to_bundle(&change);
+            if matches.is_present("stable-file-ids") {
+                json.stabilize_file_ids();
+            }
+            if let Some(recorder) = load_cargo_config.expansion_recorder.as_ref() {
+                expand_all(change);
+                json.set_expansions(recorder.take());
+            }
+            primed.iter().for_each(|krate| println!("{}", krate));
+            let panicked = primed.iter().filter(|krate| krate.panic.is_some()).count();
+            if panicked > 0 {
+                let message = format!("analysis of {} crates panicked", panicked);
+                return Err(CliError::new(ErrorKind::Analysis, message));
+            }
+            match matches.value_of("sysroot-output") {
+                Some(sysroot_path) => {
+                    let sysroot_src = load_cargo_config.sysroot_src.as_deref();
+                    let sysroot_src = sysroot_src_dir(path, sysroot_src).ok_or_else(|| {
+                        CliError::new(
+                            ErrorKind::SysrootMissing,
+                            "the sysroot sources are required to split off the sysroot",
+                        )
+                    })?;
+                    // paths in the bundle may or may not have their symlinks resolved
+                    let prefixes = Some(sysroot_src.clone())
+                        .into_iter()
+                        .chain(sysroot_src.canonicalize().ok())
+                        .map(|dir| dir.to_string_lossy().into_owned())
+                        .collect::<Vec<_>>();
+                    let (sysroot, project) = json
+                        .split_sysroot(&|path| {
+                            prefixes
+                                .iter()
+                                .any(|prefix| path.starts_with(prefix.as_str()))
+                        })
+                        .map_err(|err| {
+                            let message = format!("{} at {}", err, sysroot_src.display());
+                            CliError::new(ErrorKind::SysrootMissing, message)
+                        })?;
+                    let sysroot_path = Path::new(sysroot_path);
+                    write_bundle(&sysroot, sysroot_path, format, compression)?;
+                    write_bundle(&project, output_path, format, compression)?;
+                }
+                None => write_bundle(&json, output_path, format, compression)?,
+            }
+        }
+        Some("watch") => {
+            let matches = matches.subcommand_matches("watch").unwrap();
+            let path = Path::new(matches.value_of("path").unwrap_or("./Cargo.toml"));
+            let (format, compression) = output_format(matches);
+            let output_path = bundle_path(matches, "output", "change", format);
+            let output_path = output_path.as_path();
+            let delta_path = bundle_path(matches, "delta-output", "delta", format);
+            let delta_path = delta_path.as_path();
+            let (cargo_config, load_cargo_config, bundle_options) =
+                extraction_options(matches, path);
+            let progress = progress_reporter(matches);
+            let (change, mut watcher, _proc_macro) =
+                watch_workspace_at(path, &cargo_config, &load_cargo_config, &progress)?;
+            let mut bundle = LiveBundle::new(&change, bundle_options);
+            write_bundle(bundle.bundle(), output_path, format, compression)?;
+            println!("Watching {} for changes", path.display());
+            while let Some(change) = watcher.next_change() {
+                let delta = bundle.update(&change);
+                write_bundle(bundle.bundle(), output_path, format, compression)?;
+                write_bundle(&delta, delta_path, format, compression)?;
+                println!("Wrote delta to {}", delta_path.display());
+            }
+        }
+        Some("serve") => {
+            let matches = matches.subcommand_matches("serve").unwrap();
+            let path = Path::new(matches.value_of("path").unwrap_or("./Cargo.toml"));
+            let addr = matches.value_of("addr").unwrap_or("127.0.0.1:8080");
+            let (cargo_config, load_cargo_config, bundle_options) =
+                extraction_options(matches, path);
+            let progress = progress_reporter(matches);
+            let (change, mut watcher, _proc_macro) =
+                watch_workspace_at(path, &cargo_config, &load_cargo_config, &progress)?;
+            let mut bundle = LiveBundle::new(&change, bundle_options);
+            let server = BundleServer::bind(addr, bundle.bundle().clone()).map_err(|err| {
+                CliError::new(
+                    ErrorKind::Io,
+                    format!("failed to listen on {}: {}", addr, err),
+                )
+            })?;
+            let server = Arc::new(server);
+            println!(
+                "Serving {} on http://{}",
+                path.display(),
+                server.server_addr()
+            );
+            {
+                let server = server.clone();
+                thread::spawn(move || server.run());
+            }
+            while let Some(change) = watcher.next_change() {
+                server.publish(bundle.update(&change));
+            }
+        }
+        Some("stdio") => {
+            let matches = matches.subcommand_matches("stdio").unwrap();
+            let mut session = Session::new(
+                |manifest: &Path| extraction_options(matches, manifest),
+                !matches.is_present("quiet"),
+            );
+            let stdin = io::stdin();
+            session
+                .run(stdin.lock(), io::stdout())
+                .map_err(|err| CliError::new(ErrorKind::Io, err.to_string()))?;
+        }
+        Some("proc-macro") => {
+            proc_macro_srv::cli::run()
+                .map_err(|err| CliError::new(ErrorKind::Io, err.to_string()))?;
+        }
+        Some("diff") => {
+            let matches = matches.subcommand_matches("diff").unwrap();
+            let old = read_bundle(Path::new(matches.value_of("old").unwrap()))?;
+            let new = read_bundle(Path::new(matches.value_of("new").unwrap()))?;
+            let (format, compression) = output_format(matches);
+            let output_path = bundle_path(matches, "output", "delta", format);
+            let delta = ChangeJson::diff(&old, &new);
+            write_bundle(&delta, &output_path, format, compression)?;
+        }
+        Some("merge") => {
+            let matches = matches.subcommand_matches("merge").unwrap();
+            let bundles = matches
+                .values_of("input")
+                .unwrap()
+                .map(|path| read_bundle(Path::new(path)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let links = matches
+                .values_of("link")
+                .into_iter()
+                .flatten()
+                .map(|link| {
+                    parse_link(link).ok_or_else(|| {
+                        CliError::new(ErrorKind::Usage, format!("invalid link `{}`", link))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let (format, compression) = output_format(matches);
+            let output_path = bundle_path(matches, "output", "change", format);
+            let merged = ChangeJson::merge(&bundles, &links)
+                .map_err(|err| CliError::new(ErrorKind::Bundle, err.to_string()))?;
+            write_bundle(&merged, &output_path, format, compression)?;
+        }
+        None => return Err(CliError::new(ErrorKind::Usage, "no subcommand given")),
+        Some(name) => {
+            let message = format!("unknown subcommand `{}`", name);
+            return Err(CliError::new(ErrorKind::Usage, message));
         }
-        None => println!("Please enter a  subcommand!"),
-        _ => println!("Your entered subcommand is invalid!"),
     }
+    Ok(())
+}
+
+/// Options of loading a workspace, honoured by every subcommand which loads one.
+fn cargo_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("features")
+            .help("Space or comma separated list of features to activate")
+            .takes_value(true)
+            .long("features")
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("all-features")
+            .help("Activate all available features")
+            .long("all-features")
+            .required(false),
+        Arg::with_name("no-default-features")
+            .help("Do not activate the `default` feature")
+            .long("no-default-features")
+            .required(false),
+        Arg::with_name("target")
+            .help("Target triple to extract cfgs and dependencies for, defaults to the host")
+            .takes_value(true)
+            .long("target")
+            .required(false),
+        Arg::with_name("no-sysroot")
+            .help("Do not load the sources of the standard library")
+            .long("no-sysroot")
+            .required(false),
+        Arg::with_name("sysroot-src")
+            .help("Path to the sources of the standard library, defaults to the ones of rustc")
+            .takes_value(true)
+            .long("sysroot-src")
+            .conflicts_with("no-sysroot")
+            .required(false),
+        Arg::with_name("run-build-scripts")
+            .help("Run build scripts and include the sources they generate")
+            .long("run-build-scripts")
+            .required(false),
+    ]
+}
+
+/// Options of the subcommands which extract a bundle from a manifest.
+fn bundle_args() -> Vec<Arg<'static, 'static>> {
+    vec![Arg::with_name("stub-sysroot")
+        .help("Add minimal core, alloc and std crates if there are no standard library sources")
+        .long("stub-sysroot")
+        .required(false)]
+}
+
+/// Options which analyze the whole workspace, only done by `create`.
+fn analysis_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("with-proc-macro")
+            .help("Load the proc macros of the workspace with a proc-macro server")
+            .long("with-proc-macro")
+            .required(false),
+        Arg::with_name("prefill-caches")
+            .help("Analyze the local crates of the bundle before writing it")
+            .long("prefill-caches")
+            .required(false),
+    ]
+}
+
+fn cargo_config(matches: &ArgMatches) -> CargoConfig {
+    let features = matches
+        .values_of("features")
+        .into_iter()
+        .flatten()
+        .flat_map(|features| features.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|feature| !feature.is_empty())
+        .map(String::from)
+        .collect();
+    CargoConfig {
+        features,
+        all_features: matches.is_present("all-features"),
+        no_default_features: matches.is_present("no-default-features"),
+        target: matches.value_of("target").map(String::from),
+        no_sysroot: matches.is_present("no-sysroot"),
+        ..CargoConfig::default()
+    }
+}
+
+/// The options of loading the workspace at `manifest` and of converting it
+/// into a bundle, shared by every subcommand which extracts one.
+fn extraction_options(
+    matches: &ArgMatches,
+    manifest: &Path,
+) -> (CargoConfig, LoadCargoConfig, BundleOptions) {
+    let mut cargo_config = cargo_config(matches);
+    let load_cargo_config = load_cargo_config(matches);
+    let stub_sysroot = matches.is_present("stub-sysroot");
+    let sysroot_src = load_cargo_config.sysroot_src.as_deref();
+    if stub_sysroot && sysroot_src_dir(manifest, sysroot_src).is_none() {
+        cargo_config.no_sysroot = true;
+    }
+    let bundle_options = BundleOptions {
+        header: bundle_header(manifest),
+        stub_sysroot,
+    };
+    (cargo_config, load_cargo_config, bundle_options)
+}
+
+fn load_cargo_config(matches: &ArgMatches) -> LoadCargoConfig {
+    LoadCargoConfig {
+        load_out_dirs_from_check: matches.is_present("run-build-scripts"),
+        with_proc_macro: matches.is_present("with-proc-macro"),
+        prefill_caches: matches.is_present("prefill-caches"),
+        sysroot_src: matches.value_of("sysroot-src").map(PathBuf::from),
+        expansion_recorder: if matches.is_present("with-proc-macro") {
+            Some(Arc::new(ExpansionRecorder::default()))
+        } else {
+            None
+        },
+    }
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .help("Bundle format, defaults to json, default outputs end in .bin for binary")
+        .takes_value(true)
+        .long("format")
+        .possible_values(&["json", "binary"])
+        .required(false)
+}
+
+fn compression_arg() -> Arg<'static, 'static> {
+    Arg::with_name("compression")
+        .help("Compression of the bundle, defaults to the output extension (.gz, .zst)")
+        .takes_value(true)
+        .long("compression")
+        .possible_values(&["none", "gzip", "zstd"])
+        .required(false)
+}
+
+fn output_format(matches: &ArgMatches) -> (BundleFormat, Option<Compression>) {
+    let format = matches
+        .value_of("format")
+        .map(|format| format.parse::<BundleFormat>().unwrap())
+        .unwrap_or(BundleFormat::Json);
+    let compression = matches
+        .value_of("compression")
+        .map(|compression| compression.parse::<Compression>().unwrap());
+    (format, compression)
+}
+
+/// The path given by the argument `arg`, or `./<name>.json` respectively
+/// `./<name>.bin` for binary bundles.
+fn bundle_path(matches: &ArgMatches, arg: &str, name: &str, format: BundleFormat) -> PathBuf {
+    match matches.value_of(arg) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let extension = match format {
+                BundleFormat::Json => "json",
+                BundleFormat::Binary => "bin",
+            };
+            PathBuf::from(format!("./{}.{}", name, extension))
+        }
+    }
+}
+
+/// Parses `<bundle>:<crate>=<bundle>:<crate>`.
+fn parse_link(link: &str) -> Option<MergeLink> {
+    let parse_crate = |text: &str| {
+        let (bundle, name) = text.split_once(':')?;
+        Some((bundle.parse::<usize>().ok()?, name.to_string()))
+    };
+    let (from, to) = link.split_once('=')?;
+    Some(MergeLink {
+        from: parse_crate(from)?,
+        to: parse_crate(to)?,
+    })
+}
+
+/// Reports the phases of loading a workspace on stderr, unless `--quiet`
+/// is given. Stdout is left to the output of the subcommands.
+fn progress_reporter(matches: &ArgMatches) -> impl Fn(LoadProgress) {
+    let quiet = matches.is_present("quiet");
+    move |progress| {
+        if !quiet {
+            eprintln!("{}", progress);
+        }
+    }
+}
+
+fn read_bundle(path: &Path) -> Result<ChangeJson, CliError> {
+    let file = File::open(path).map_err(|err| CliError::io(path, err))?;
+    Ok(ChangeJson::from_reader(file)?)
+}
+
+fn write_bundle(
+    json: &ChangeJson,
+    path: &Path,
+    format: BundleFormat,
+    compression: Option<Compression>,
+) -> Result<(), CliError> {
+    let compression = compression.unwrap_or_else(|| Compression::from_path(path));
+    let bytes = json.to_bytes(format)?;
+    let bytes = compress(&bytes, compression)
+        .map_err(|err| CliError::new(ErrorKind::Bundle, format!("compression failed: {}", err)))?;
+    fs::write(path, bytes).map_err(|err| CliError::io(path, err))
+}
+
+fn bundle_header(manifest: &Path) -> BundleHeader {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or_default();
+    BundleHeader::new(
+        env!("CARGO_PKG_VERSION"),
+        rustc_version(manifest),
+        created_at,
+    )
+}
+
+/// Asks the toolchain which applies to the project for its version, so
+/// `rust-toolchain` files are respected.
+fn rustc_version(manifest: &Path) -> Option<String> {
+    rustc(manifest, &["--version"])
+}
+
+/// Finds the absolute path of the standard library sources the same way
+/// `project_model` does, honoring `RUST_SRC_PATH` and the `sysroot_src` of
+/// rust-project.json.
+fn sysroot_src_dir(manifest: &Path, sysroot_src: Option<&Path>) -> Option<PathBuf> {
+    let dir = env::current_dir().ok()?;
+    if let Some(sysroot_src) = sysroot_src {
+        return Some(dir.join(sysroot_src));
+    }
+    if manifest.extension().map_or(false, |ext| ext == "json") {
+        return project_json_sysroot_src(manifest).map(|src| dir.join(src));
+    }
+    if let Some(path) = env::var_os("RUST_SRC_PATH") {
+        return Some(dir.join(path));
+    }
+    let sysroot = PathBuf::from(rustc(manifest, &["--print", "sysroot"])?);
+    let src = sysroot.join("lib/rustlib/src/rust");
+    ["library", "src"]
+        .iter()
+        .map(|dir| src.join(dir))
+        .find(|dir| dir.exists())
+}
+
+/// The `sysroot_src` of a rust-project.json, which is relative to the file.
+fn project_json_sysroot_src(manifest: &Path) -> Option<PathBuf> {
+    let project: serde_json::Value = serde_json::from_reader(File::open(manifest).ok()?).ok()?;
+    let sysroot_src = project.get("sysroot_src")?.as_str()?;
+    Some(manifest.parent()?.join(sysroot_src))
+}
+
+fn rustc(manifest: &Path, args: &[&str]) -> Option<String> {
+    let dir = manifest.parent().filter(|it| !it.as_os_str().is_empty());
+    let mut cmd = Command::new("rustc");
+    cmd.args(args);
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    let output = cmd.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|it| it.trim().to_string())
 }
 
 #[cfg(test)]
@@ -83,8 +707,10 @@ mod tests {
             load_out_dirs_from_check: false,
             with_proc_macro: false,
             prefill_caches: false,
+            sysroot_src: None,
+            expansion_recorder: None,
         };
-        let (change, _vfs, _proc_macro) =
+        let (change, _vfs, _proc_macro, _primed) =
             load_workspace_at(path, &cargo_config, &load_cargo_config, &|_| {}).unwrap();
         let json = ChangeJson::from(&change);
         let text = serde_json::to_string(&json).expect("serialization of change must work");
@@ -92,4 +718,215 @@ mod tests {
             serde_json::from_str(&text).expect("deserialization of change must work");
         let _change = json.to_change();
     }
+
+    #[test]
+    fn binary_bundle_round_trip() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/rust-project/rust-project.json");
+        let load_cargo_config = LoadCargoConfig::default();
+        let (change, _vfs, _proc_macro, _primed) =
+            load_workspace_at(&path, &CargoConfig::default(), &load_cargo_config, &|_| {}).unwrap();
+        let json = ChangeJson::from(&change);
+        let bytes = json.to_bytes(BundleFormat::Binary).unwrap();
+        assert_eq!(BundleFormat::detect(&bytes), BundleFormat::Binary);
+        assert_eq!(ChangeJson::from_bytes(&bytes).unwrap(), json);
+    }
+
+    #[test]
+    fn test_rust_project_json() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/rust-project/rust-project.json");
+        let cargo_config = CargoConfig::default();
+        let load_cargo_config = LoadCargoConfig::default();
+        let (change, _vfs, _proc_macro, _primed) =
+            load_workspace_at(&path, &cargo_config, &load_cargo_config, &|_| {}).unwrap();
+        let (change, warnings) = ChangeJson::from(&change)
+            .try_to_change()
+            .expect("bundle must be consistent");
+        assert_eq!(warnings, vec![]);
+        let graph = change.crate_graph.unwrap();
+        let krate = |name: &str| {
+            graph
+                .iter()
+                .find(|id| graph[*id].display_name.as_deref() == Some(name))
+                .unwrap()
+        };
+
+        let app = &graph[krate("app")];
+        let deps = app
+            .dependencies
+            .iter()
+            .map(|dep| dep.name.to_string())
+            .collect::<Vec<_>>();
+        assert!(deps.contains(&"util".to_string()));
+        assert!(deps.contains(&"core".to_string()));
+        assert_eq!(app.env.get("APP_MODE").as_deref(), Some("test"));
+        let keys = app.cfg_options.get_cfg_keys();
+        assert!(keys.iter().any(|key| key.as_str() == "app_cfg"));
+        let util = &graph[krate("util")];
+        let features = util.cfg_options.get_cfg_values("feature");
+        assert!(features.iter().any(|value| value.as_str() == "std"));
+        // the dylib is only loaded with a proc-macro server, and then a missing
+        // one must not fail the extraction either
+        assert!(graph[krate("derive")].proc_macro.is_empty());
+    }
+
+    /// The cfgs and dependencies of the crate in `tests/fixtures/features`.
+    struct FixtureCrate {
+        features: Vec<String>,
+        potential_features: Vec<String>,
+        target_os: Vec<String>,
+        deps: Vec<String>,
+    }
+
+    /// Extracts the `features` fixture with the given command line options.
+    fn extract_features_fixture(args: &[&str]) -> FixtureCrate {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/features/Cargo.toml");
+        // the standard library is left out unless its sources are given
+        let no_sysroot = Some("--no-sysroot").filter(|_| !args.contains(&"--sysroot-src"));
+        let args = Some("create")
+            .into_iter()
+            .chain(no_sysroot)
+            .chain(args.iter().copied());
+        let matches = App::new("create")
+            .args(&cargo_args())
+            .get_matches_from(args);
+        let (change, _vfs, _proc_macro, _primed) = load_workspace_at(
+            &path,
+            &cargo_config(&matches),
+            &load_cargo_config(&matches),
+            &|_| {},
+        )
+        .unwrap();
+        let graph = change.crate_graph.unwrap();
+        let krate = graph
+            .iter()
+            .find(|id| graph[*id].display_name.as_deref() == Some("features"))
+            .unwrap();
+        let data = &graph[krate];
+        let sorted = |mut values: Vec<String>| {
+            values.sort();
+            values
+        };
+        let features = data.cfg_options.get_cfg_values("feature");
+        let potential_features = data.potential_cfg_options.get_cfg_values("feature");
+        let target_os = data.cfg_options.get_cfg_values("target_os");
+        FixtureCrate {
+            features: sorted(features.iter().map(|it| it.to_string()).collect()),
+            potential_features: sorted(
+                potential_features.iter().map(|it| it.to_string()).collect(),
+            ),
+            target_os: sorted(target_os.iter().map(|it| it.to_string()).collect()),
+            deps: sorted(
+                data.dependencies
+                    .iter()
+                    .map(|dep| dep.name.to_string())
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn feature_flags_select_cfgs() {
+        let krate = extract_features_fixture(&[]);
+        assert_eq!(krate.features, ["default", "std"]);
+        assert_eq!(krate.potential_features, ["default", "extra", "std"]);
+
+        let krate = extract_features_fixture(&["--no-default-features", "--features", "extra"]);
+        assert_eq!(krate.features, ["extra"]);
+        assert_eq!(krate.potential_features, ["default", "extra", "std"]);
+
+        let krate = extract_features_fixture(&["--no-default-features", "--features", "std,extra"]);
+        assert_eq!(krate.features, ["extra", "std"]);
+
+        let krate = extract_features_fixture(&["--all-features"]);
+        assert_eq!(krate.features, ["default", "extra", "std"]);
+    }
+
+    #[test]
+    fn target_selects_cfgs_and_deps() {
+        let krate = extract_features_fixture(&["--target", "x86_64-pc-windows-msvc"]);
+        assert_eq!(krate.target_os, ["windows"]);
+        assert_eq!(krate.deps, ["windows_only"]);
+
+        let krate = extract_features_fixture(&["--target", "x86_64-unknown-linux-gnu"]);
+        assert_eq!(krate.target_os, ["linux"]);
+        assert_eq!(krate.deps, ["unix_only"]);
+    }
+
+    #[test]
+    fn sysroot_src_is_loaded() {
+        let sysroot_src =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/rust-project/sysroot");
+        let krate = extract_features_fixture(&["--sysroot-src", sysroot_src.to_str().unwrap()]);
+        assert!(krate.deps.contains(&"core".to_string()));
+        assert!(krate.deps.contains(&"std".to_string()));
+
+        let args = ["create", "--no-sysroot", "--sysroot-src", "sysroot"];
+        let matches = App::new("create")
+            .args(&cargo_args())
+            .get_matches_from_safe(&args);
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn missing_manifest_exit_code() {
+        let path = Path::new("does/not/exist/Cargo.toml");
+        let load_cargo_config = LoadCargoConfig::default();
+        let err = load_workspace_at(path, &CargoConfig::default(), &load_cargo_config, &|_| {})
+            .map(|_| ())
+            .unwrap_err();
+        let err = CliError::from(err);
+        assert_eq!(err.exit_code(), 3);
+        assert_eq!(err.to_json()["kind"], "manifest_not_found");
+    }
+
+    #[test]
+    fn load_progress_phases() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/rust-project/rust-project.json");
+        let load_cargo_config = LoadCargoConfig::default();
+        let events = std::cell::RefCell::new(Vec::new());
+        let progress = |progress: LoadProgress| events.borrow_mut().push(progress);
+        load_workspace_at(
+            &path,
+            &CargoConfig::default(),
+            &load_cargo_config,
+            &progress,
+        )
+        .unwrap();
+        let events = events.into_inner();
+        assert!(matches!(events.first(), Some(LoadProgress::Metadata(_))));
+        assert!(events.contains(&LoadProgress::CrateGraph));
+        match events.last() {
+            Some(LoadProgress::Files { n_done, n_total }) => assert_eq!(n_done, n_total),
+            event => panic!("expected files to be loaded last, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_are_usage_errors() {
+        let args = ["crate_extractor", "create", "--no-such-flag"];
+        let err = CliError::from(app().get_matches_from_safe(&args).unwrap_err());
+        assert_eq!(err.exit_code(), 2);
+
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            error_format(&args(&[
+                "crate_extractor",
+                "--error-format",
+                "json",
+                "create"
+            ])),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            error_format(&args(&["crate_extractor", "create", "--error-format=json"])),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            error_format(&args(&["crate_extractor", "create", "--no-such-flag"])),
+            ErrorFormat::Human
+        );
+    }
 }
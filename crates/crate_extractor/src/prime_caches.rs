@@ -0,0 +1,128 @@
+use std::{
+    any::Any,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use hir::db::DefDatabase;
+use ide::{AnalysisHost, Change};
+use ide_db::base_db::{SourceDatabase, SourceDatabaseExt};
+
+/// Outcome of priming the caches of one local crate.
+#[derive(Debug)]
+pub struct CratePriming {
+    pub name: String,
+    /// Includes the time spent on dependencies which were not primed before.
+    pub duration: Duration,
+    /// Message of the panic raised while analyzing the crate, if any.
+    pub panic: Option<String>,
+}
+
+impl fmt::Display for CratePriming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.panic {
+            None => write!(f, "{}: {:.2?}", self.name, self.duration),
+            Some(panic) => write!(
+                f,
+                "{}: panicked after {:.2?}: {}",
+                self.name, self.duration, panic
+            ),
+        }
+    }
+}
+
+/// Loads `change` into a fresh database and computes the parse trees, item
+/// trees and def maps of every local crate, in dependency order.
+///
+/// Pass the change of the finished bundle, e.g. `ChangeJson::to_change`, to
+/// check that what is shipped really analyzes.
+pub fn prime_caches(change: Change) -> Vec<CratePriming> {
+    let mut host = AnalysisHost::default();
+    host.apply_change(change);
+    let db = host.raw_database();
+    let graph = db.crate_graph();
+    graph
+        .crates_in_topological_order()
+        .into_iter()
+        .filter(|krate| {
+            let source_root = db.file_source_root(graph[*krate].root_file_id);
+            !db.source_root(source_root).is_library
+        })
+        .map(|krate| {
+            let name = graph[krate]
+                .display_name
+                .as_ref()
+                .map_or_else(|| format!("{:?}", krate), |name| name.to_string());
+            let start = Instant::now();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                db.crate_def_map(krate);
+            }));
+            CratePriming {
+                name,
+                duration: start.elapsed(),
+                panic: result.err().map(|err| panic_message(&*err)),
+            }
+        })
+        .collect()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ide_db::base_db::{Env, ProcMacroExpander, ProcMacroExpansionError};
+
+    use super::*;
+    use crate::test_utils::derive_change;
+
+    #[derive(Debug)]
+    struct PanickingExpander;
+
+    impl ProcMacroExpander for PanickingExpander {
+        fn expand(
+            &self,
+            _subtree: &tt::Subtree,
+            _attrs: Option<&tt::Subtree>,
+            _env: &Env,
+        ) -> Result<tt::Subtree, ProcMacroExpansionError> {
+            panic!("expander exploded")
+        }
+    }
+
+    #[test]
+    fn panics_are_captured_per_crate() {
+        let change = derive_change(
+            Arc::new(PanickingExpander),
+            &[
+                ("fine", "pub struct Fine;", true),
+                (
+                    "broken",
+                    "#[derive(answer::Answer)]\npub struct Broken;",
+                    true,
+                ),
+            ],
+        );
+        let primed = prime_caches(change);
+        // the library crate `answer` is not primed
+        assert_eq!(primed.len(), 2);
+        assert!(primed.iter().all(|krate| krate.duration > Duration::ZERO));
+        let krate = |name: &str| primed.iter().find(|krate| krate.name == name).unwrap();
+        assert_eq!(krate("fine").panic, None);
+        assert!(krate("fine").to_string().starts_with("fine: "));
+        assert_eq!(krate("broken").panic.as_deref(), Some("expander exploded"));
+        assert!(krate("broken")
+            .to_string()
+            .starts_with("broken: panicked after "));
+    }
+}
@@ -5,6 +5,8 @@ use proc_macro_api::{MacroDylib, ProcMacroServer};
 use project_model::ProjectWorkspace;
 use vfs::{file_set::FileSetConfig, AbsPath, AbsPathBuf};
 
+use crate::expansions::ExpansionRecorder;
+
 #[derive(Default)]
 pub(crate) struct ProjectFolders {
     pub(crate) load: Vec<vfs::loader::Entry>,
@@ -89,7 +91,11 @@ impl SourceRootConfig {
     }
 }
 
-pub(crate) fn load_proc_macro(client: Option<&ProcMacroServer>, path: &AbsPath) -> Vec<ProcMacro> {
+pub(crate) fn load_proc_macro(
+    client: Option<&ProcMacroServer>,
+    recorder: Option<&Arc<ExpansionRecorder>>,
+    path: &AbsPath,
+) -> Vec<ProcMacro> {
     let dylib = match MacroDylib::new(path.to_path_buf()) {
         Ok(it) => it,
         Err(err) => {
@@ -115,17 +121,20 @@ pub(crate) fn load_proc_macro(client: Option<&ProcMacroServer>, path: &AbsPath)
                 Vec::new()
             }
         })
-        .map(expander_to_proc_macro)
+        .map(|expander| expander_to_proc_macro(expander, recorder.cloned()))
         .collect();
 
-    fn expander_to_proc_macro(expander: proc_macro_api::ProcMacro) -> ProcMacro {
+    fn expander_to_proc_macro(
+        expander: proc_macro_api::ProcMacro,
+        recorder: Option<Arc<ExpansionRecorder>>,
+    ) -> ProcMacro {
         let name = expander.name().into();
         let kind = match expander.kind() {
             proc_macro_api::ProcMacroKind::CustomDerive => ProcMacroKind::CustomDerive,
             proc_macro_api::ProcMacroKind::FuncLike => ProcMacroKind::FuncLike,
             proc_macro_api::ProcMacroKind::Attr => ProcMacroKind::Attr,
         };
-        let expander = Arc::new(Expander(expander));
+        let expander = Arc::new(Expander { expander, recorder });
         ProcMacro {
             name,
             kind,
@@ -134,7 +143,10 @@ pub(crate) fn load_proc_macro(client: Option<&ProcMacroServer>, path: &AbsPath)
     }
 
     #[derive(Debug)]
-    struct Expander(proc_macro_api::ProcMacro);
+    struct Expander {
+        expander: proc_macro_api::ProcMacro,
+        recorder: Option<Arc<ExpansionRecorder>>,
+    }
 
     impl ProcMacroExpander for Expander {
         fn expand(
@@ -143,9 +155,15 @@ pub(crate) fn load_proc_macro(client: Option<&ProcMacroServer>, path: &AbsPath)
             attrs: Option<&tt::Subtree>,
             env: &Env,
         ) -> Result<tt::Subtree, ProcMacroExpansionError> {
+            let key = change_json::expansion_key(self.expander.name(), subtree, attrs, env);
             let env = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
-            match self.0.expand(subtree, attrs, env) {
-                Ok(Ok(subtree)) => Ok(subtree),
+            match self.expander.expand(subtree, attrs, env) {
+                Ok(Ok(subtree)) => {
+                    if let Some(recorder) = self.recorder.as_ref() {
+                        recorder.record(key, &subtree);
+                    }
+                    Ok(subtree)
+                }
                 Ok(Err(err)) => Err(ProcMacroExpansionError::Panic(err.0)),
                 Err(err) => Err(ProcMacroExpansionError::System(err.to_string())),
             }
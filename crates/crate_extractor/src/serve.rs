@@ -0,0 +1,378 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Cursor},
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use change_json::{BundleFormat, ChangeJson, Compression};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::compression::compress;
+
+/// How many deltas are kept for clients which fell behind.
+const MAX_DELTAS: usize = 64;
+
+/// How long a request for the next delta waits before it is answered with
+/// `204 No Content`.
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serves a bundle over HTTP, together with the deltas published since.
+///
+/// - `GET /change.json` answers with the current bundle, and its version in
+///   the `X-Bundle-Version` header.
+/// - `GET /delta?since=<version>` answers with the delta which turns version
+///   `since` into `since + 1`, waiting for it if necessary. Clients which fell
+///   too far behind get `410 Gone`, and clients ahead of the server, e.g.
+///   after a restart, `400 Bad Request`; both have to fetch the bundle again.
+///
+/// Both take a `format` query parameter, `json` or `binary`, and compress
+/// their response according to `Accept-Encoding`.
+pub struct BundleServer {
+    server: Server,
+    state: Arc<State>,
+}
+
+struct State {
+    bundles: Mutex<Bundles>,
+    published: Condvar,
+}
+
+struct Bundles {
+    json: ChangeJson,
+    version: u64,
+    /// The last delta turns `version - 1` into `version`.
+    deltas: VecDeque<ChangeJson>,
+    /// The current bundle in the encodings requested so far.
+    encoded: Vec<EncodedBundle>,
+}
+
+struct EncodedBundle {
+    version: u64,
+    format: BundleFormat,
+    compression: Compression,
+    bytes: Arc<Vec<u8>>,
+}
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+impl BundleServer {
+    pub fn bind(addr: impl ToSocketAddrs, json: ChangeJson) -> io::Result<BundleServer> {
+        let server = Server::http(addr).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let bundles = Bundles {
+            json,
+            version: 0,
+            deltas: VecDeque::new(),
+            encoded: Vec::new(),
+        };
+        let state = State {
+            bundles: Mutex::new(bundles),
+            published: Condvar::new(),
+        };
+        Ok(BundleServer {
+            server,
+            state: Arc::new(state),
+        })
+    }
+
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server.server_addr()
+    }
+
+    /// Applies `delta` to the served bundle and hands it to waiting clients.
+    pub fn publish(&self, delta: ChangeJson) {
+        let mut bundles = self.state.bundles.lock().unwrap();
+        bundles.json.apply(&delta);
+        bundles.deltas.push_back(delta);
+        if bundles.deltas.len() > MAX_DELTAS {
+            bundles.deltas.pop_front();
+        }
+        bundles.version += 1;
+        bundles.encoded.clear();
+        self.state.published.notify_all();
+    }
+
+    /// Answers requests, each on its own thread, until
+    /// [`BundleServer::shutdown`] is called.
+    pub fn run(&self) {
+        for request in self.server.incoming_requests() {
+            let state = self.state.clone();
+            thread::spawn(move || {
+                if let Err(err) = state.respond(request) {
+                    log::warn!("failed to answer request: {}", err);
+                }
+            });
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.server.unblock();
+    }
+}
+
+impl State {
+    fn respond(&self, request: Request) -> io::Result<()> {
+        let url = request.url().to_string();
+        let (path, query) = match url.find('?') {
+            Some(idx) => (&url[..idx], &url[idx + 1..]),
+            None => (url.as_str(), ""),
+        };
+        let format = query_param(query, "format").map_or(Ok(BundleFormat::Json), str::parse);
+        let compression = accepted_compression(&request);
+        let response = match (request.method(), path, format) {
+            (Method::Get, _, Err(_)) => status(400),
+            (Method::Get, "/change.json", Ok(format)) => {
+                let (bytes, version) = self.encoded_bundle(format, compression)?;
+                bundle_response(bytes.to_vec(), version, format, compression)
+            }
+            (Method::Get, "/delta", Ok(format)) => {
+                match query_param(query, "since").and_then(|since| since.parse().ok()) {
+                    Some(since) => match self.wait_for_delta(since) {
+                        Ok(Some(delta)) => {
+                            let bytes = encode(&delta, format, compression)?;
+                            bundle_response(bytes, since + 1, format, compression)
+                        }
+                        Ok(None) => status(204),
+                        Err(code) => status(code),
+                    },
+                    None => status(400),
+                }
+            }
+            _ => status(404),
+        };
+        // the web IDE is usually served from another origin
+        let response = response
+            .with_header(header("Cache-Control", "no-cache"))
+            .with_header(header("Access-Control-Allow-Origin", "*"))
+            .with_header(header("Access-Control-Expose-Headers", "X-Bundle-Version"));
+        request.respond(response)
+    }
+
+    /// Returns the current bundle in the requested encoding, and its version.
+    /// The bundle is encoded without holding the lock, since compressing it
+    /// takes a while, and then cached until the next delta is published.
+    fn encoded_bundle(
+        &self,
+        format: BundleFormat,
+        compression: Compression,
+    ) -> io::Result<(Arc<Vec<u8>>, u64)> {
+        let (json, version) = {
+            let bundles = self.bundles.lock().unwrap();
+            let cached = bundles
+                .encoded
+                .iter()
+                .find(|encoded| encoded.format == format && encoded.compression == compression);
+            if let Some(encoded) = cached {
+                return Ok((encoded.bytes.clone(), encoded.version));
+            }
+            (bundles.json.clone(), bundles.version)
+        };
+        let bytes = Arc::new(encode(&json, format, compression)?);
+        let mut bundles = self.bundles.lock().unwrap();
+        if bundles.version == version {
+            bundles.encoded.push(EncodedBundle {
+                version,
+                format,
+                compression,
+                bytes: bytes.clone(),
+            });
+        }
+        Ok((bytes, version))
+    }
+
+    /// Waits until the delta following version `since` is published, or
+    /// fails with the status code to answer with.
+    fn wait_for_delta(&self, since: u64) -> Result<Option<ChangeJson>, u16> {
+        let bundles = self.bundles.lock().unwrap();
+        if since > bundles.version {
+            return Err(400);
+        }
+        let (bundles, _) = self
+            .published
+            .wait_timeout_while(bundles, POLL_TIMEOUT, |bundles| bundles.version <= since)
+            .unwrap();
+        if bundles.version <= since {
+            return Ok(None);
+        }
+        let oldest = bundles.version - bundles.deltas.len() as u64;
+        if since < oldest {
+            return Err(410);
+        }
+        Ok(Some(bundles.deltas[(since - oldest) as usize].clone()))
+    }
+}
+
+fn encode(
+    json: &ChangeJson,
+    format: BundleFormat,
+    compression: Compression,
+) -> io::Result<Vec<u8>> {
+    let bytes = json
+        .to_bytes(format)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    compress(&bytes, compression)
+}
+
+fn bundle_response(
+    bytes: Vec<u8>,
+    version: u64,
+    format: BundleFormat,
+    compression: Compression,
+) -> HttpResponse {
+    let content_type = match format {
+        BundleFormat::Json => "application/json",
+        BundleFormat::Binary => "application/octet-stream",
+    };
+    let mut response = Response::from_data(bytes)
+        .with_header(header("Content-Type", content_type))
+        .with_header(header("X-Bundle-Version", &version.to_string()));
+    let encoding = match compression {
+        Compression::None => None,
+        Compression::Gzip => Some("gzip"),
+        Compression::Zstd => Some("zstd"),
+    };
+    if let Some(encoding) = encoding {
+        response = response.with_header(header("Content-Encoding", encoding));
+    }
+    response
+}
+
+fn status(code: u16) -> HttpResponse {
+    Response::from_data(Vec::new()).with_status_code(code)
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("header must be valid")
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Picks the best compression the client accepts, preferring zstd.
+fn accepted_compression(request: &Request) -> Compression {
+    let accepted = request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Accept-Encoding"))
+        .flat_map(|header| header.value.as_str().split(','))
+        .map(|encoding| encoding.split(';').next().unwrap_or("").trim())
+        .collect::<Vec<_>>();
+    if accepted.contains(&"zstd") {
+        Compression::Zstd
+    } else if accepted.contains(&"gzip") {
+        Compression::Gzip
+    } else {
+        Compression::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use ide::Change;
+    use ide_db::base_db::FileId;
+
+    use super::*;
+
+    struct HttpResult {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl HttpResult {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(field, _)| field.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    fn get(addr: SocketAddr, path: &str, accept_encoding: &str) -> HttpResult {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\nConnection: close\r\n\r\n",
+            path, accept_encoding
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response
+            .windows(4)
+            .position(|it| it == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        let mut lines = head.lines();
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(field, value)| (field.to_string(), value.trim().to_string()))
+            .collect();
+        HttpResult {
+            status: status.parse().unwrap(),
+            headers,
+            body: response[split + 4..].to_vec(),
+        }
+    }
+
+    #[test]
+    fn serve_bundle_and_deltas() {
+        let server = Arc::new(BundleServer::bind("127.0.0.1:0", ChangeJson::default()).unwrap());
+        let addr = server.server_addr();
+        let running = {
+            let server = server.clone();
+            thread::spawn(move || server.run())
+        };
+
+        let bundle = get(addr, "/change.json", "gzip");
+        assert_eq!(bundle.status, 200);
+        assert_eq!(bundle.header("Content-Type"), Some("application/json"));
+        assert_eq!(bundle.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(bundle.header("X-Bundle-Version"), Some("0"));
+        let json = ChangeJson::from_reader(&bundle.body[..]).unwrap();
+        assert_eq!(json, ChangeJson::default());
+
+        let poll = thread::spawn(move || get(addr, "/delta?since=0&format=binary", "identity"));
+        let mut change = Change::new();
+        change.change_file(FileId(0), Some(Arc::new("fn main() {}".to_string())));
+        let delta = ChangeJson::from(&change);
+        server.publish(delta.clone());
+        let polled = poll.join().unwrap();
+        assert_eq!(polled.status, 200);
+        assert_eq!(
+            polled.header("Content-Type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(polled.header("X-Bundle-Version"), Some("1"));
+        assert_eq!(ChangeJson::from_bytes(&polled.body).unwrap(), delta);
+
+        let bundle = get(addr, "/change.json", "zstd, gzip;q=0.5");
+        assert_eq!(bundle.header("Content-Encoding"), Some("zstd"));
+        assert_eq!(bundle.header("X-Bundle-Version"), Some("1"));
+        assert_eq!(ChangeJson::from_reader(&bundle.body[..]).unwrap(), delta);
+        let cached = get(addr, "/change.json", "zstd");
+        assert_eq!(cached.header("X-Bundle-Version"), Some("1"));
+        assert_eq!(cached.body, bundle.body);
+
+        assert_eq!(get(addr, "/delta?since=2", "identity").status, 400);
+        assert_eq!(get(addr, "/delta?since=x", "identity").status, 400);
+        assert_eq!(get(addr, "/change.json?format=xml", "identity").status, 400);
+        assert_eq!(get(addr, "/other", "identity").status, 404);
+
+        server.shutdown();
+        running.join().unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+//! Line-delimited JSON protocol, for hosts which drive the extraction
+//! through stdin and stdout instead of files.
+//!
+//! Every line of the input is a request like
+//! `{"id": 1, "method": "extract", "manifest": "Cargo.toml"}`, answered by one
+//! line like `{"id": 1, "result": "bundle", "bundle": {..}}`. While it is
+//! handled, notifications like
+//! `{"id": 1, "notification": "progress", "message": ".."}` may come first.
+
+use std::{
+    cell::RefCell,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use change_json::ChangeJson;
+use proc_macro_api::ProcMacroServer;
+use project_model::CargoConfig;
+use serde::{Deserialize, Serialize};
+use vfs::AbsPathBuf;
+
+use crate::{
+    bundle::{BundleOptions, LiveBundle},
+    load_change::{open_workspace_at, LoadCargoConfig, LoadProgress},
+    watch::Watcher,
+};
+
+#[derive(Deserialize, Debug)]
+struct Request {
+    /// Echoed in the response, so that hosts can match them up.
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    method: Method,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Method {
+    /// Loads the workspace of a `Cargo.toml` or `rust-project.json`, answered
+    /// with the bundle.
+    Extract { manifest: PathBuf },
+    /// Reads files of the loaded workspace from disk again, answered with a
+    /// delta.
+    Reread { paths: Vec<PathBuf> },
+    /// Selects the features of following extractions. If a workspace is
+    /// loaded, it is extracted again and answered with the new bundle; if
+    /// that fails, the workspace and features stay as they were.
+    SetFeatures {
+        #[serde(default)]
+        features: Vec<String>,
+        #[serde(default)]
+        all_features: bool,
+        #[serde(default)]
+        no_default_features: bool,
+    },
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    result: Reply,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum Reply {
+    Ok,
+    Bundle { bundle: ChangeJson },
+    Delta { delta: ChangeJson },
+    Error { message: String },
+}
+
+/// Sent while a request is handled, with the `id` of that request.
+#[derive(Serialize, Debug)]
+struct Notification<'a> {
+    id: &'a Option<serde_json::Value>,
+    #[serde(flatten)]
+    event: Event,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "notification", rename_all = "snake_case")]
+enum Event {
+    /// A phase of loading the workspace, see [`LoadProgress`].
+    Progress { message: String },
+}
+
+/// The workspace extracted last, with its files as they were read.
+struct Workspace {
+    manifest: PathBuf,
+    watcher: Watcher,
+    bundle: LiveBundle,
+    _proc_macro: Option<ProcMacroServer>,
+}
+
+/// The features selected with `set_features`.
+#[derive(Debug, Clone, PartialEq)]
+struct Features {
+    features: Vec<String>,
+    all_features: bool,
+    no_default_features: bool,
+}
+
+type ExtractionOptions<'a> = dyn Fn(&Path) -> (CargoConfig, LoadCargoConfig, BundleOptions) + 'a;
+
+pub struct Session<'a> {
+    options: Box<ExtractionOptions<'a>>,
+    /// Replaces the features given by `options`.
+    features: Option<Features>,
+    report_progress: bool,
+    workspace: Option<Workspace>,
+}
+
+impl<'a> Session<'a> {
+    /// `options` gives the options of extracting each manifest, like the
+    /// header of its bundle. With `report_progress` the phases of loading a
+    /// workspace are sent as `progress` notifications.
+    pub fn new(
+        options: impl Fn(&Path) -> (CargoConfig, LoadCargoConfig, BundleOptions) + 'a,
+        report_progress: bool,
+    ) -> Self {
+        Session {
+            options: Box::new(options),
+            features: None,
+            report_progress,
+            workspace: None,
+        }
+    }
+
+    /// Answers the requests read from `input` until it ends.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(Request { id, method }) => {
+                    let report_progress = self.report_progress;
+                    let notifications = RefCell::new(&mut output);
+                    let progress = |progress: LoadProgress| {
+                        if report_progress {
+                            let notification = Notification {
+                                id: &id,
+                                event: Event::Progress {
+                                    message: progress.to_string(),
+                                },
+                            };
+                            // a broken output fails the response as well
+                            let _ = write_line(&mut **notifications.borrow_mut(), &notification);
+                        }
+                    };
+                    let result = self.handle(method, &progress);
+                    Response { id, result }
+                }
+                Err(err) => Response {
+                    id: None,
+                    result: Reply::Error {
+                        message: format!("invalid request: {}", err),
+                    },
+                },
+            };
+            write_line(&mut output, &response)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, method: Method, progress: &dyn Fn(LoadProgress)) -> Reply {
+        match method {
+            Method::Extract { manifest } => self.extract(manifest, progress),
+            Method::Reread { paths } => match self.workspace.as_mut() {
+                Some(workspace) => {
+                    let cwd = match std::env::current_dir() {
+                        Ok(cwd) => cwd,
+                        Err(err) => return error(err),
+                    };
+                    let paths = paths
+                        .iter()
+                        .map(|path| AbsPathBuf::assert(cwd.join(path)))
+                        .collect::<Vec<_>>();
+                    let change = workspace.watcher.reread(&paths);
+                    Reply::Delta {
+                        delta: workspace.bundle.update(&change),
+                    }
+                }
+                None => error("no workspace has been extracted yet"),
+            },
+            Method::SetFeatures {
+                features,
+                all_features,
+                no_default_features,
+            } => {
+                let previous = self.features.replace(Features {
+                    features,
+                    all_features,
+                    no_default_features,
+                });
+                let manifest = match &self.workspace {
+                    Some(workspace) => workspace.manifest.clone(),
+                    None => return Reply::Ok,
+                };
+                // the loaded workspace stays if the features don't work out
+                let reply = self.extract(manifest, progress);
+                if let Reply::Error { .. } = reply {
+                    self.features = previous;
+                }
+                reply
+            }
+        }
+    }
+
+    fn extract(&mut self, manifest: PathBuf, progress: &dyn Fn(LoadProgress)) -> Reply {
+        let (mut cargo_config, load_config, bundle_options) = (self.options)(&manifest);
+        if let Some(features) = self.features.clone() {
+            cargo_config.features = features.features;
+            cargo_config.all_features = features.all_features;
+            cargo_config.no_default_features = features.no_default_features;
+        }
+        let res = open_workspace_at(&manifest, &cargo_config, &load_config, false, progress);
+        match res {
+            Ok((change, watcher, proc_macro)) => {
+                let bundle = LiveBundle::new(&change, bundle_options);
+                let reply = Reply::Bundle {
+                    bundle: bundle.bundle().clone(),
+                };
+                self.workspace = Some(Workspace {
+                    manifest,
+                    watcher,
+                    bundle,
+                    _proc_macro: proc_macro,
+                });
+                reply
+            }
+            Err(err) => error(err),
+        }
+    }
+}
+
+fn write_line(output: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    serde_json::to_writer(&mut *output, value)?;
+    output.write_all(b"\n")?;
+    output.flush()
+}
+
+fn error(err: impl ToString) -> Reply {
+    Reply::Error {
+        message: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_utils::TempProject;
+
+    #[test]
+    fn extract_and_reread() {
+        let project = TempProject::new("stdio", &[("lib.rs", "pub fn f() {}")]);
+        let mut session = Session::new(|_: &Path| Default::default(), true);
+        let notifications = RefCell::new(Vec::new());
+        let mut request = |value: serde_json::Value| {
+            let mut output = Vec::new();
+            session
+                .run(format!("{}\n", value).as_bytes(), &mut output)
+                .unwrap();
+            let mut lines = serde_json::Deserializer::from_slice(&output)
+                .into_iter::<serde_json::Value>()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>();
+            let response = lines.pop().unwrap();
+            *notifications.borrow_mut() = lines;
+            response
+        };
+        let manifest = project.manifest();
+        let lib = project.path("lib.rs");
+
+        let response = request(serde_json::json!({"id": 1, "method": "reread", "paths": [lib]}));
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"], "error");
+
+        let response =
+            request(serde_json::json!({"id": 2, "method": "extract", "manifest": manifest}));
+        assert_eq!(response["result"], "bundle");
+        let crates = &response["bundle"]["crate_graph"]["crates"];
+        assert_eq!(crates.as_array().unwrap().len(), 1);
+        assert!(!notifications.borrow().is_empty());
+        for notification in notifications.borrow().iter() {
+            assert_eq!(notification["id"], 2);
+            assert_eq!(notification["notification"], "progress");
+        }
+
+        fs::write(&lib, "pub fn g() {}").unwrap();
+        let response = request(serde_json::json!({"id": 3, "method": "reread", "paths": [lib]}));
+        assert_eq!(response["result"], "delta");
+        let files = &response["delta"]["files_changed"]["files"];
+        assert_eq!(files[0][1], "pub fn g() {}");
+        assert!(response["delta"]["crate_graph"].is_null());
+
+        let response = request(serde_json::json!({"method": "set_features", "features": ["std"]}));
+        assert_eq!(response["result"], "bundle");
+
+        fs::write(&manifest, "{").unwrap();
+        let response = request(serde_json::json!({"method": "set_features", "features": ["x"]}));
+        assert_eq!(response["result"], "error");
+        fs::write(&lib, "pub fn h() {}").unwrap();
+        let response = request(serde_json::json!({"method": "reread", "paths": [lib]}));
+        assert_eq!(response["result"], "delta");
+        let features = session.features.unwrap().features;
+        assert_eq!(features, vec!["std".to_string()]);
+    }
+}
@@ -0,0 +1,102 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::{fs, path::PathBuf, sync::Arc};
+
+use ide::Change;
+use ide_db::base_db::{
+    CrateDisplayName, CrateGraph, CrateName, Edition, Env, FileId, FileSet, ProcMacro,
+    ProcMacroExpander, ProcMacroKind, SourceRoot, VfsPath,
+};
+
+/// A change with the library crate `answer`, whose derive `Answer` is
+/// expanded by `expander`, and one crate for each of `crates`, given as
+/// `(name, text, is_local)`, which all depend on `answer`.
+pub(crate) fn derive_change(
+    expander: Arc<dyn ProcMacroExpander>,
+    crates: &[(&str, &str, bool)],
+) -> Change {
+    let answer = (
+        "answer",
+        "#[proc_macro_derive(Answer)]\npub fn answer(item: TokenStream) -> TokenStream { item }",
+        false,
+    );
+    let mut graph = CrateGraph::default();
+    let mut change = Change::new();
+    let mut roots = Vec::new();
+    let mut answer_id = None;
+    for (id, (name, text, is_local)) in Some(&answer).into_iter().chain(crates).enumerate() {
+        let file_id = FileId(id as u32);
+        change.change_file(file_id, Some(Arc::new(text.to_string())));
+        let mut file_set = FileSet::default();
+        let path = format!("/{}/lib.rs", name);
+        file_set.insert(file_id, VfsPath::new_virtual_path(path));
+        roots.push(if *is_local {
+            SourceRoot::new_local(file_set)
+        } else {
+            SourceRoot::new_library(file_set)
+        });
+        let proc_macro = match answer_id {
+            None => vec![ProcMacro {
+                name: "Answer".into(),
+                kind: ProcMacroKind::CustomDerive,
+                expander: expander.clone(),
+            }],
+            Some(_) => Vec::new(),
+        };
+        let krate = graph.add_crate_root(
+            file_id,
+            Edition::Edition2018,
+            Some(CrateDisplayName::from_canonical_name(name.to_string())),
+            Default::default(),
+            Default::default(),
+            Env::default(),
+            proc_macro,
+        );
+        match answer_id {
+            None => answer_id = Some(krate),
+            Some(answer) => graph
+                .add_dep(krate, CrateName::new("answer").unwrap(), answer)
+                .unwrap(),
+        }
+    }
+    change.set_roots(roots);
+    change.set_crate_graph(graph);
+    change
+}
+
+/// A temporary directory with a `rust-project.json` of a single crate with
+/// the root module `lib.rs`, removed again when dropped.
+pub(crate) struct TempProject {
+    dir: PathBuf,
+}
+
+impl TempProject {
+    /// Writes `files`, given as `(path, text)`, next to the `rust-project.json`.
+    pub(crate) fn new(name: &str, files: &[(&str, &str)]) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("crate_extractor_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let project = r#"{"crates": [
+            {"root_module": "lib.rs", "edition": "2018", "deps": [], "cfg": [], "env": {}}
+        ]}"#;
+        fs::write(dir.join("rust-project.json"), project).unwrap();
+        for (path, text) in files {
+            fs::write(dir.join(path), text).unwrap();
+        }
+        TempProject { dir }
+    }
+
+    pub(crate) fn manifest(&self) -> PathBuf {
+        self.path("rust-project.json")
+    }
+
+    pub(crate) fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+}
+
+impl Drop for TempProject {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
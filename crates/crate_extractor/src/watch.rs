@@ -0,0 +1,144 @@
+use std::{
+    fs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::Receiver;
+use ide::Change;
+use vfs::{
+    loader::{Handle, Message},
+    AbsPathBuf, VfsPath,
+};
+
+use crate::reload::SourceRootConfig;
+
+/// Keeps the files of a loaded workspace up to date with the disk.
+pub struct Watcher {
+    pub(crate) vfs: vfs::Vfs,
+    // watching stops once the loader is dropped
+    pub(crate) _loader: Box<dyn Handle>,
+    pub(crate) receiver: Receiver<Message>,
+    pub(crate) source_root_config: SourceRootConfig,
+}
+
+impl Watcher {
+    pub fn vfs(&self) -> &vfs::Vfs {
+        &self.vfs
+    }
+
+    /// Blocks until watched files change and returns a [`Change`] with their
+    /// contents, which also has new source roots if files were created or
+    /// deleted. Returns `None` once the loader stopped.
+    pub fn next_change(&mut self) -> Option<Change> {
+        self.next_change_until(None)
+    }
+
+    /// Like [`Watcher::next_change`], but also returns `None` if nothing
+    /// changed within `timeout`.
+    pub fn next_change_timeout(&mut self, timeout: Duration) -> Option<Change> {
+        self.next_change_until(Some(Instant::now() + timeout))
+    }
+
+    fn next_change_until(&mut self, deadline: Option<Instant>) -> Option<Change> {
+        loop {
+            let message = match deadline {
+                Some(deadline) => self.receiver.recv_deadline(deadline).ok()?,
+                None => self.receiver.recv().ok()?,
+            };
+            self.handle(message);
+            // editors often write several files at once
+            while let Ok(message) = self.receiver.recv_timeout(Duration::from_millis(50)) {
+                self.handle(message);
+            }
+
+            if let Some(change) = self.take_change() {
+                return Some(change);
+            }
+        }
+    }
+
+    /// Reads `paths` from disk again and returns a [`Change`] like
+    /// [`Watcher::next_change`], which is empty if none of them changed.
+    pub fn reread(&mut self, paths: &[AbsPathBuf]) -> Change {
+        for path in paths {
+            let contents = fs::read(path).ok();
+            self.vfs
+                .set_file_contents(VfsPath::from(path.clone()), contents);
+        }
+        self.take_change().unwrap_or_default()
+    }
+
+    fn take_change(&mut self) -> Option<Change> {
+        let changes = self.vfs.take_changes();
+        if changes.is_empty() {
+            return None;
+        }
+        let mut change = Change::new();
+        let roots_changed = changes.iter().any(|file| file.is_created_or_deleted());
+        for file in changes {
+            let text = if file.exists() {
+                let contents = self.vfs.file_contents(file.file_id).to_vec();
+                String::from_utf8(contents).ok().map(Arc::new)
+            } else {
+                None
+            };
+            change.change_file(file.file_id, text);
+        }
+        if roots_changed {
+            change.set_roots(self.source_root_config.partition(&self.vfs));
+        }
+        Some(change)
+    }
+
+    fn handle(&mut self, message: Message) {
+        if let Message::Loaded { files } = message {
+            for (path, contents) in files {
+                self.vfs.set_file_contents(path.into(), contents);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use project_model::CargoConfig;
+
+    use super::*;
+    use crate::{
+        bundle::LiveBundle,
+        load_change::{watch_workspace_at, LoadCargoConfig},
+        test_utils::TempProject,
+    };
+
+    #[test]
+    fn delta_contains_the_changed_file() {
+        let project = TempProject::new("watch", &[("lib.rs", "mod a;"), ("a.rs", "pub fn a() {}")]);
+        let load_config = LoadCargoConfig::default();
+        let (change, mut watcher, _proc_macro) = watch_workspace_at(
+            &project.manifest(),
+            &CargoConfig::default(),
+            &load_config,
+            &|_| {},
+        )
+        .unwrap();
+        let mut bundle = LiveBundle::new(&change, Default::default());
+
+        fs::write(project.path("a.rs"), "pub fn b() {}").unwrap();
+        let change = watcher
+            .next_change_timeout(Duration::from_secs(5))
+            .expect("the change was not noticed within 5 seconds");
+        let a = AbsPathBuf::assert(project.path("a.rs"));
+        let a = watcher.vfs().file_id(&VfsPath::from(a)).unwrap();
+        let text = Some(Arc::new("pub fn b() {}".to_string()));
+        assert_eq!(change.files_changed, vec![(a, text)]);
+        assert!(change.roots.is_none());
+        let delta = serde_json::to_value(bundle.update(&change)).unwrap();
+        assert_eq!(
+            delta["files_changed"]["files"],
+            serde_json::json!([[a.0, "pub fn b() {}"]])
+        );
+        assert!(delta["crate_graph"].is_null());
+        assert!(delta["local_roots"].is_null());
+    }
+}
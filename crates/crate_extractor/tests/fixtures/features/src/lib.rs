@@ -0,0 +1,5 @@
+#[cfg(feature = "std")]
+pub fn with_std() {}
+
+#[cfg(feature = "extra")]
+pub fn with_extra() {}
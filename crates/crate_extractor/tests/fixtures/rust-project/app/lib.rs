@@ -0,0 +1,3 @@
+pub fn app() -> u32 {
+    util::util()
+}
@@ -0,0 +1,4 @@
+#[cfg(feature = "std")]
+pub fn util() -> u32 {
+    1
+}